@@ -4,11 +4,18 @@ use winit::event_loop::EventLoop;
 use wasm_bindgen::prelude::*;
 
 use crate::app::App;
-use crate::procgen::{
-    WaveFunctionCollapse, WorldDefinition, bitmap_to_voxels, make_island_race_tileset,
-};
+use crate::procgen::{GenerationReport, OverlappingModel, WorldDefinition, bitmap_to_voxels};
 use crate::scene::Scene;
 
+pub use crate::procgen::{
+    Amount, BacktrackConfig, CountConstraints, Generator, GifRecorder, Guide, GuideRule, LintIssue,
+    Material, MaterialId, MaterialRegistry, OverlappingConfig, Pin, ProgressObserver, Propagator,
+    Region, RoadConstraint, SelectionHeuristic, SolverState, TileCount, Tileset,
+    WaveFunctionCollapse, WfcConfig, WfcObserver, island_race_pins, lint_tileset,
+    lint_tileset_file, load_tileset, make_island_race_tileset, parse_tileset_json,
+    parse_tileset_xml,
+};
+
 mod app;
 mod buffer;
 mod camera;
@@ -17,36 +24,200 @@ mod procgen;
 mod scene;
 mod texture;
 
-pub fn run_wfc(seed: u64, n: usize, output_prefix: &str, make_gif: bool) -> anyhow::Result<()> {
+pub fn run_wfc(
+    generator: &Generator,
+    seed: u64,
+    output_prefix: &str,
+    make_gif: bool,
+) -> anyhow::Result<()> {
+    let gif = make_gif.then(|| GifRecorder::new(output_prefix.to_owned() + ".gif"));
+    let mut observers = (ProgressObserver::default(), gif);
+    let (wfc, report) = generator.run(seed, &mut observers)?;
+    println!("{}", describe_report(&report));
+    if let Some(gif) = observers.1 {
+        gif.finish()?;
+    }
+
+    save_world(&wfc, report.seed, output_prefix)
+}
+
+/// Saves the image and world definition of a finished run. `seed` seeds the height map
+fn save_world(wfc: &WaveFunctionCollapse, seed: u64, output_prefix: &str) -> anyhow::Result<()> {
     let img_path = output_prefix.to_owned() + ".png";
     let world_path = output_prefix.to_owned() + ".json";
 
-    let tileset = make_island_race_tileset();
-    let mut wfc = WaveFunctionCollapse::new(tileset, n, n, seed);
-    let (_, bitmaps) = wfc.step_all(true, make_gif);
-    if make_gif {
-        let gif_path = output_prefix.to_owned() + ".gif";
-        let gif_file = std::fs::File::create(&gif_path)?;
-        let mut encoder = image::codecs::gif::GifEncoder::new(gif_file);
-        encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
-
-        let frames = bitmaps.iter().map(|b| {
-            let img = b.render_to_image();
-            let scaled = img.resize_exact(
-                img.width() * 10,
-                img.height() * 10,
-                image::imageops::FilterType::Nearest,
-            );
-            image::Frame::new(scaled.to_rgba8())
-        });
-        encoder.encode_frames(frames)?;
-    }
+    // tilesets with images are rendered at full resolution, bitmap ones one pixel per bit
     let bitmap = wfc.bitmap();
-    let img = bitmap.render_to_image();
+    let img = match wfc.render() {
+        Ok(img) => img,
+        Err(_) => bitmap.render_to_image(),
+    };
     img.save(img_path)?;
 
     let height_map = bitmap.compute_height_map(seed);
-    let world_def = WorldDefinition { bitmap, height_map };
+    let world_def = WorldDefinition {
+        bitmap,
+        height_map,
+        periodic: wfc.wave.periodic,
+    };
+
+    let json = serde_json::to_string(&world_def)?;
+    std::fs::write(world_path, json)?;
+
+    Ok(())
+}
+
+/// Runs a single WFC attempt for at most `stop_after` steps (until it finishes if `None`) and
+/// saves the solver state to `state_path`, to be continued by [`run_resume`]. There are no
+/// restarts, since the saved state has to continue the attempt. Saves the world as well if the
+/// run already finished
+pub fn run_wfc_until(
+    generator: &Generator,
+    seed: u64,
+    stop_after: Option<usize>,
+    state_path: &str,
+    output_prefix: &str,
+) -> anyhow::Result<()> {
+    let mut wfc = WaveFunctionCollapse::new(
+        generator.tileset.clone(),
+        generator.width,
+        generator.height,
+        seed,
+        generator.config.clone(),
+    )?;
+    while !wfc.is_finished() && stop_after.is_none_or(|steps| wfc.steps() < steps) {
+        if let Err(contradiction) = wfc.step(&mut ()) {
+            // keep the failed run around to look at or regenerate from
+            wfc.snapshot().save(state_path)?;
+            println!("saved state at the contradiction to {}", state_path);
+            return Err(contradiction.into());
+        }
+    }
+
+    wfc.snapshot().save(state_path)?;
+    if wfc.is_finished() {
+        println!("finished after {} steps", wfc.steps());
+        save_world(&wfc, seed, output_prefix)?;
+    } else {
+        println!("saved state after {} steps to {}", wfc.steps(), state_path);
+    }
+    Ok(())
+}
+
+/// Continues a run saved by [`run_wfc_until`] until it finishes, and saves the world like
+/// [`run_wfc`]. `tileset` has to be the one the run started with
+pub fn run_resume(tileset: Tileset, state_path: &str, output_prefix: &str) -> anyhow::Result<()> {
+    let state = SolverState::load(state_path)?;
+    let seed = state.seed;
+    let mut wfc = WaveFunctionCollapse::restore(tileset, state)?;
+    let steps = wfc.steps();
+
+    wfc.step_all(&mut ProgressObserver::default())?;
+    println!(
+        "resumed at step {} and finished after {} steps",
+        steps,
+        wfc.steps()
+    );
+    save_world(&wfc, seed, output_prefix)
+}
+
+/// Regenerates `region` (in wave tiles) of a world saved by [`run_wfc`] with the same tileset,
+/// keeping everything around it. The height map outside of the region stays the same too, and
+/// the region wraps around the edges if the world was generated with them wrapping
+pub fn run_regen(
+    tileset: Tileset,
+    world_path: &str,
+    region: Region,
+    seed: u64,
+    output_prefix: &str,
+    config: WfcConfig,
+    max_attempts: usize,
+) -> anyhow::Result<()> {
+    let img_path = output_prefix.to_owned() + ".png";
+    let out_world_path = output_prefix.to_owned() + ".json";
+
+    let json = std::fs::read_to_string(world_path)?;
+    let world_def: WorldDefinition = serde_json::from_str(&json)?;
+    world_def.validate()?;
+
+    let tile_size = tileset.tile_size;
+    let generator = Generator {
+        tileset,
+        width: world_def.bitmap.width / tile_size,
+        height: world_def.bitmap.height / tile_size,
+        config: WfcConfig {
+            periodic: world_def.periodic,
+            ..config
+        },
+        max_attempts,
+    };
+    let mut progress = ProgressObserver::default();
+    let (wfc, report) = generator.regenerate(&world_def.bitmap, region, seed, &mut progress)?;
+    println!("{}", describe_report(&report));
+
+    let bitmap = wfc.bitmap();
+    let img = match wfc.render() {
+        Ok(img) => img,
+        Err(_) => bitmap.render_to_image(),
+    };
+    img.save(img_path)?;
+
+    let mut height_map = bitmap.compute_height_map(report.seed);
+    let old_heights = &world_def.height_map;
+    for y in 0..bitmap.height {
+        for x in 0..bitmap.width {
+            if !region.contains(x / tile_size, y / tile_size) {
+                let idx = y * bitmap.width + x;
+                height_map.bottoms[idx] = old_heights.bottoms[idx];
+                height_map.tops[idx] = old_heights.tops[idx];
+            }
+        }
+    }
+    let world_def = WorldDefinition {
+        bitmap,
+        height_map,
+        periodic: world_def.periodic,
+    };
+
+    let json = serde_json::to_string(&world_def)?;
+    std::fs::write(out_world_path, json)?;
+
+    Ok(())
+}
+
+/// Learns patterns from the sample image and generates a `width` x `height` image in the same
+/// style. Also saves a world definition, mapping every color to the closest bit
+pub fn run_overlapping(
+    sample_path: &str,
+    seed: u64,
+    width: usize,
+    height: usize,
+    output_prefix: &str,
+    config: OverlappingConfig,
+    max_attempts: usize,
+) -> anyhow::Result<()> {
+    let img_path = output_prefix.to_owned() + ".png";
+    let world_path = output_prefix.to_owned() + ".json";
+
+    let sample = image::open(sample_path)?;
+    let model = OverlappingModel::from_image(&sample, &config)?;
+    println!(
+        "learned {} patterns from {}",
+        model.num_patterns(),
+        sample_path
+    );
+
+    let (grid, report) = model.generate(width, height, seed, max_attempts)?;
+    println!("{}", describe_report(&report));
+    grid.render_to_image().save(img_path)?;
+
+    let bitmap = grid.to_bitmap();
+    let height_map = bitmap.compute_height_map(report.seed);
+    let world_def = WorldDefinition {
+        bitmap,
+        height_map,
+        periodic: config.periodic,
+    };
 
     let json = serde_json::to_string(&world_def)?;
     std::fs::write(world_path, json)?;
@@ -54,11 +225,45 @@ pub fn run_wfc(seed: u64, n: usize, output_prefix: &str, make_gif: bool) -> anyh
     Ok(())
 }
 
+/// Prints every problem found in the tileset file, or in the built-in island tileset if no path
+/// is given. Errors if there are any
+pub fn run_tileset_lint(path: Option<&str>, subset: Option<&str>) -> anyhow::Result<()> {
+    let issues = match path {
+        Some(path) => lint_tileset_file(path, subset)?,
+        None => lint_tileset(&make_island_race_tileset()),
+    };
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    if !issues.is_empty() {
+        anyhow::bail!("found {} problem(s)", issues.len());
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+fn describe_report(report: &GenerationReport) -> String {
+    let mut description = format!(
+        "generated with seed {} after {} attempt(s)",
+        report.seed, report.attempts
+    );
+    for failure in &report.failures {
+        description += &format!(
+            "\n  seed {} failed at step {}: {}",
+            failure.seed, failure.step, failure.contradiction
+        );
+    }
+    description
+}
+
 pub fn run_interactive(
     do_postprocess: bool,
-    n: usize,
+    width: usize,
+    height: usize,
     seed: u64,
     world_path: Option<&str>,
+    config: WfcConfig,
+    max_attempts: usize,
 ) -> anyhow::Result<()> {
     #[cfg(not(target_arch = "wasm32"))]
     {
@@ -74,14 +279,25 @@ pub fn run_interactive(
     let voxels = if let Some(world_path) = world_path {
         let json = std::fs::read_to_string(world_path)?;
         let world_def: WorldDefinition = serde_json::from_str(&json)?;
+        world_def.validate()?;
         bitmap_to_voxels(world_def)
     } else {
-        let tileset = make_island_race_tileset();
-        let mut wfc = WaveFunctionCollapse::new(tileset, n, n, seed);
-        wfc.step_all(true, false);
+        let generator = Generator {
+            tileset: make_island_race_tileset(),
+            width,
+            height,
+            config,
+            max_attempts,
+        };
+        let (wfc, report) = generator.run(seed, &mut ProgressObserver::default())?;
+        log::info!("{}", describe_report(&report));
         let bitmap = wfc.bitmap();
-        let height_map = bitmap.compute_height_map(seed);
-        let world_def = WorldDefinition { bitmap, height_map };
+        let height_map = bitmap.compute_height_map(report.seed);
+        let world_def = WorldDefinition {
+            bitmap,
+            height_map,
+            periodic: generator.config.periodic,
+        };
         bitmap_to_voxels(world_def)
     };
 
@@ -1,6 +1,11 @@
 use anyhow::Error;
-use clap::{Parser, Subcommand};
-use placeholder_name_lib::{run_interactive, run_wfc};
+use clap::{Args, Parser, Subcommand};
+use placeholder_name_lib::{
+    BacktrackConfig, CountConstraints, Generator, Guide, OverlappingConfig, Pin, Propagator,
+    Region, RoadConstraint, SelectionHeuristic, TileCount, WfcConfig, island_race_pins,
+    load_tileset, make_island_race_tileset, run_interactive, run_overlapping, run_regen,
+    run_resume, run_tileset_lint, run_wfc, run_wfc_until,
+};
 
 #[derive(Parser)]
 #[command(name = "placeholder-name")]
@@ -9,6 +14,144 @@ struct Cli {
     command: Commands,
 }
 
+/// Solver options shared by every command that runs WFC
+#[derive(Args)]
+struct WfcArgs {
+    /// Undo observations on contradiction instead of stopping
+    #[arg(long, default_value_t = false)]
+    backtrack: bool,
+
+    /// Max number of observations that can be undone
+    #[arg(long, default_value = "64")]
+    backtrack_depth: usize,
+
+    /// Max number of undos before giving up
+    #[arg(long, default_value = "1000")]
+    backtrack_retries: usize,
+
+    /// How constraints are propagated: masks or support-count
+    #[arg(long, default_value = "masks")]
+    propagator: Propagator,
+
+    /// How the next tile to observe is picked: entropy, mrv or scanline
+    #[arg(long, default_value = "mrv")]
+    heuristic: SelectionHeuristic,
+
+    /// Keep every road bit in one network: connected, or loop for a closed track without dead
+    /// ends or forks
+    #[arg(long)]
+    roads: Option<RoadConstraint>,
+
+    /// Bound how many times a tile shows up, as tile=count or tile=min..max where counts can be
+    /// percentages of the output, e.g. road_end=2 or pure_space=..5% (repeatable)
+    #[arg(long)]
+    count: Vec<TileCount>,
+
+    /// Minimum number of tiles with road on them
+    #[arg(long)]
+    min_road_length: Option<usize>,
+
+    /// JSON file with count constraints, combined with --count and --min-road-length
+    #[arg(long)]
+    constraints: Option<String>,
+
+    /// Guide image steering which tiles go where. It's stretched over the output, and
+    /// --guide-legend says what its colors mean
+    #[arg(long, requires = "guide_legend")]
+    guide: Option<String>,
+
+    /// JSON file mapping guide colors to the tiles they allow or reweight, like
+    /// {"#0000ff": {"tiles": ["pure_space"]}, "#ff0000": {"road": true}}
+    #[arg(long, requires = "guide")]
+    guide_legend: Option<String>,
+
+    /// Fix a tile before generating, as x,y,tile[,rotation] (repeatable). Defaults to a road end
+    /// at (5, 5) with the built-in tileset (a straight road for loops)
+    #[arg(long)]
+    pin: Vec<Pin>,
+
+    /// Don't pin any tiles, not even the default road end
+    #[arg(long, default_value_t = false, conflicts_with = "pin")]
+    no_pins: bool,
+
+    /// Number of times to rerun WFC with a new seed after a contradiction
+    #[arg(long, default_value = "10")]
+    attempts: usize,
+}
+
+impl WfcArgs {
+    /// `default_pins` are used unless pins were given or disabled. Errors if the constraints
+    /// file or guide can't be read
+    fn config(&self, default_pins: Vec<Pin>) -> Result<WfcConfig, Error> {
+        let pins = if self.no_pins {
+            Vec::new()
+        } else if self.pin.is_empty() {
+            default_pins
+        } else {
+            self.pin.clone()
+        };
+
+        let mut counts = match &self.constraints {
+            Some(path) => CountConstraints::from_file(path)?,
+            None => CountConstraints::default(),
+        };
+        counts.tiles.extend(self.count.iter().cloned());
+        if self.min_road_length.is_some() {
+            counts.min_road_length = self.min_road_length;
+        }
+
+        let guide = match (&self.guide, &self.guide_legend) {
+            (Some(image), Some(legend)) => Some(Guide::load(image, legend)?),
+            _ => None,
+        };
+
+        Ok(WfcConfig {
+            backtracking: self.backtrack.then_some(BacktrackConfig {
+                max_depth: self.backtrack_depth,
+                max_retries: self.backtrack_retries,
+            }),
+            propagator: self.propagator,
+            heuristic: self.heuristic,
+            pins,
+            periodic: false,
+            roads: self.roads,
+            counts,
+            guide,
+        })
+    }
+}
+
+/// Size of the generated output
+#[derive(Args)]
+struct SizeArgs {
+    /// Width and height of the output, in tiles (or in pixels for the overlapping model)
+    #[arg(short, long, default_value = "10")]
+    n: usize,
+
+    /// Width of the output (overrides -n)
+    #[arg(long)]
+    width: Option<usize>,
+
+    /// Height of the output (overrides -n)
+    #[arg(long)]
+    height: Option<usize>,
+}
+
+impl SizeArgs {
+    /// (width, height). Errors if either is 0
+    fn dims(&self) -> Result<(usize, usize), Error> {
+        let (width, height) = (self.width.unwrap_or(self.n), self.height.unwrap_or(self.n));
+        if width == 0 || height == 0 {
+            return Err(anyhow::anyhow!(
+                "size has to be at least 1x1, got {}x{}",
+                width,
+                height
+            ));
+        }
+        Ok((width, height))
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run interactive mode
@@ -25,9 +168,11 @@ enum Commands {
         #[arg(short, long, default_value = "17")]
         seed: u64,
 
-        /// Width/height of WFC wave (default: 10)
-        #[arg(short, long, default_value = "10")]
-        n: usize,
+        #[command(flatten)]
+        size: SizeArgs,
+
+        #[command(flatten)]
+        wfc: WfcArgs,
     },
     // /// Render scene
     // RenderScene {
@@ -48,21 +193,154 @@ enum Commands {
     //     dont_postprocess: bool,
     // },
     /// Run WFC and save to file
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Wfc {
+        #[command(subcommand)]
+        command: Option<Box<WfcCommands>>,
+
         /// Output file path
-        path: String,
+        #[arg(required = true)]
+        path: Option<String>,
 
         /// Seed for WFC generation
         #[arg(short, long, default_value = "17")]
         seed: u64,
 
-        /// Width/height of WFC wave (default: 10)
-        #[arg(short, long, default_value = "10")]
-        n: usize,
+        #[command(flatten)]
+        size: SizeArgs,
 
         /// Make gif
         #[arg(long, default_value_t = false)]
         make_gif: bool,
+
+        /// Wrap around the edges so the output tiles seamlessly
+        #[arg(long, default_value_t = false)]
+        periodic: bool,
+
+        /// Tileset to generate from instead of the built-in island tileset: a bitmap tileset
+        /// (.json) or tileset XML, whose tile images are loaded from the same directory
+        #[arg(long)]
+        tileset: Option<String>,
+
+        /// Only use the tiles in this subset of the tileset XML
+        #[arg(long, requires = "tileset")]
+        subset: Option<String>,
+
+        /// Save the solver state to this file, to be continued with `wfc resume`. Only one
+        /// attempt is made
+        #[arg(long, conflicts_with = "make_gif")]
+        save_state: Option<String>,
+
+        /// Stop after this many steps
+        #[arg(long, requires = "save_state")]
+        stop_after: Option<usize>,
+
+        #[command(flatten)]
+        wfc: WfcArgs,
+    },
+    /// Run overlapping-model WFC on a sample image and save to file
+    Overlapping {
+        /// Sample image to learn patterns from
+        sample: String,
+
+        /// Output file path
+        path: String,
+
+        /// Seed for WFC generation
+        #[arg(short, long, default_value = "17")]
+        seed: u64,
+
+        #[command(flatten)]
+        size: SizeArgs,
+
+        /// Width and height of the patterns extracted from the sample
+        #[arg(long, default_value = "3")]
+        pattern_size: usize,
+
+        /// Don't learn rotated copies of the patterns
+        #[arg(long, default_value_t = false)]
+        no_rotations: bool,
+
+        /// Don't learn mirrored copies of the patterns
+        #[arg(long, default_value_t = false)]
+        no_reflections: bool,
+
+        /// Let patterns wrap around the edges of the sample
+        #[arg(long, default_value_t = false)]
+        periodic_input: bool,
+
+        /// Wrap around the edges so the output tiles seamlessly
+        #[arg(long, default_value_t = false)]
+        periodic: bool,
+
+        /// Number of times to rerun WFC with a new seed after a contradiction
+        #[arg(long, default_value = "10")]
+        attempts: usize,
+    },
+    /// Inspect tilesets
+    Tileset {
+        #[command(subcommand)]
+        command: TilesetCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum WfcCommands {
+    /// Generate a region of a saved world again, keeping the rest of it
+    Regen {
+        /// World saved by the wfc command
+        world: String,
+
+        /// Output file path
+        path: String,
+
+        /// Wave tiles to generate again, as x,y,width,height
+        #[arg(long)]
+        region: Region,
+
+        /// Seed for WFC generation
+        #[arg(short, long, default_value = "17")]
+        seed: u64,
+
+        /// Tileset the world was generated from, if it wasn't the built-in island tileset
+        #[arg(long)]
+        tileset: Option<String>,
+
+        /// Only use the tiles in this subset of the tileset XML
+        #[arg(long, requires = "tileset")]
+        subset: Option<String>,
+
+        #[command(flatten)]
+        wfc: Box<WfcArgs>,
+    },
+    /// Continue a run saved with --save-state until it finishes
+    Resume {
+        /// Solver state saved by the wfc command
+        state: String,
+
+        /// Output file path
+        path: String,
+
+        /// Tileset the run started with, if it wasn't the built-in island tileset
+        #[arg(long)]
+        tileset: Option<String>,
+
+        /// Only use the tiles in this subset of the tileset XML
+        #[arg(long, requires = "tileset")]
+        subset: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TilesetCommands {
+    /// Report adjacency problems that can cause contradictions
+    Lint {
+        /// Tileset file (.json or XML) to check. Checks the built-in island tileset if not given
+        path: Option<String>,
+
+        /// Only check the tiles in this subset of the tileset XML
+        #[arg(long, requires = "path")]
+        subset: Option<String>,
     },
 }
 
@@ -72,11 +350,21 @@ fn main() -> Result<(), Error> {
     match args.command {
         Commands::Interactive {
             dont_postprocess,
-            n,
+            size,
             seed,
             world,
+            wfc,
         } => {
-            run_interactive(!dont_postprocess, n, seed, world.as_deref())?;
+            let (width, height) = size.dims()?;
+            run_interactive(
+                !dont_postprocess,
+                width,
+                height,
+                seed,
+                world.as_deref(),
+                wfc.config(island_race_pins(width, height, wfc.roads))?,
+                wfc.attempts,
+            )?;
         }
         // Commands::RenderScene {
         //     path,
@@ -88,13 +376,105 @@ fn main() -> Result<(), Error> {
         //     render_scene_to_file(&from, &path, width, height, !dont_postprocess)?;
         // }
         Commands::Wfc {
+            command: Some(command),
+            ..
+        } => match *command {
+            WfcCommands::Regen {
+                world,
+                path,
+                region,
+                seed,
+                tileset,
+                subset,
+                wfc,
+            } => {
+                let tileset = match tileset {
+                    Some(tileset_path) => load_tileset(tileset_path, subset.as_deref())?,
+                    None => make_island_race_tileset(),
+                };
+                // the surroundings are already there, so nothing is pinned by default
+                let config = wfc.config(Vec::new())?;
+                run_regen(tileset, &world, region, seed, &path, config, wfc.attempts)?;
+            }
+            WfcCommands::Resume {
+                state,
+                path,
+                tileset,
+                subset,
+            } => {
+                let tileset = match tileset {
+                    Some(tileset_path) => load_tileset(tileset_path, subset.as_deref())?,
+                    None => make_island_race_tileset(),
+                };
+                run_resume(tileset, &state, &path)?;
+            }
+        },
+        Commands::Wfc {
+            command: None,
             path,
             seed,
-            n,
+            size,
             make_gif,
+            periodic,
+            tileset,
+            subset,
+            save_state,
+            stop_after,
+            wfc,
+        } => {
+            let (width, height) = size.dims()?;
+            let (tileset, default_pins) = match tileset {
+                Some(tileset_path) => (load_tileset(tileset_path, subset.as_deref())?, Vec::new()),
+                None => (
+                    make_island_race_tileset(),
+                    island_race_pins(width, height, wfc.roads),
+                ),
+            };
+            let generator = Generator {
+                tileset,
+                width,
+                height,
+                config: WfcConfig {
+                    periodic,
+                    ..wfc.config(default_pins)?
+                },
+                max_attempts: wfc.attempts,
+            };
+            let path = path.expect("clap requires a path without a subcommand");
+            match save_state {
+                Some(state_path) => {
+                    run_wfc_until(&generator, seed, stop_after, &state_path, &path)?
+                }
+                None => run_wfc(&generator, seed, &path, make_gif)?,
+            }
+        }
+        Commands::Overlapping {
+            sample,
+            path,
+            seed,
+            size,
+            pattern_size,
+            no_rotations,
+            no_reflections,
+            periodic_input,
+            periodic,
+            attempts,
         } => {
-            run_wfc(seed, n, &path, make_gif)?;
+            let (width, height) = size.dims()?;
+            let config = OverlappingConfig {
+                pattern_size,
+                rotations: !no_rotations,
+                reflections: !no_reflections,
+                periodic_input,
+                periodic,
+            };
+            run_overlapping(&sample, seed, width, height, &path, config, attempts)?;
         }
+        Commands::Tileset { command } => match command {
+            TilesetCommands::Lint { path, subset } => {
+                run_tileset_lint(path.as_deref(), subset.as_deref())?;
+            }
+        },
     }
 
     Ok(())
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+
+/// Fixed-size set of option indices packed into `u64` words
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitSet {
+    words: Vec<u64>,
+    size: usize,
+}
+
+impl BitSet {
+    /// set that can hold indices `0..size`, with none of them present
+    pub fn empty(size: usize) -> Self {
+        Self {
+            words: vec![0; size.div_ceil(64)],
+            size,
+        }
+    }
+
+    /// set that holds every index in `0..size`
+    pub fn full(size: usize) -> Self {
+        let mut set = Self {
+            words: vec![u64::MAX; size.div_ceil(64)],
+            size,
+        };
+        set.clear_padding();
+        set
+    }
+
+    /// bits past `size` in the last word are always kept at 0
+    fn clear_padding(&mut self) {
+        let used = self.size % 64;
+        if used != 0 {
+            let last = self.words.len() - 1;
+            self.words[last] &= (1 << used) - 1;
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, i: usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    #[inline]
+    pub fn remove(&mut self, i: usize) {
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    /// number of indices in both sets
+    #[inline]
+    pub fn intersection_count(&self, other: &BitSet) -> usize {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .map(|(a, b)| (a & b).count_ones() as usize)
+            .sum()
+    }
+
+    /// adds every index in `other`
+    #[inline]
+    pub fn union_with(&mut self, other: &BitSet) {
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a |= b;
+        }
+    }
+
+    /// removes every index not in `other`. Returns whether anything was removed
+    #[inline]
+    pub fn intersect_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            let new = *a & b;
+            changed |= new != *a;
+            *a = new;
+        }
+        changed
+    }
+
+    /// whether every index in the set is also in `other`
+    #[inline]
+    pub fn is_subset(&self, other: &BitSet) -> bool {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .all(|(a, b)| a & !b == 0)
+    }
+
+    /// indices in the set, in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(word_idx, &word)| {
+            let mut remaining = word;
+            std::iter::from_fn(move || {
+                if remaining == 0 {
+                    return None;
+                }
+                let bit = remaining.trailing_zeros() as usize;
+                remaining &= remaining - 1;
+                Some(word_idx * 64 + bit)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_span_several_words() {
+        for size in [1, 63, 64, 65, 130] {
+            let full = BitSet::full(size);
+            assert_eq!(full.count(), size);
+            assert_eq!(
+                full.iter().collect::<Vec<_>>(),
+                (0..size).collect::<Vec<_>>()
+            );
+
+            let mut set = BitSet::empty(size);
+            assert!(set.is_empty());
+            for i in (0..size).step_by(7) {
+                set.insert(i);
+            }
+            set.insert(size - 1);
+            let expected: Vec<usize> = (0..size).filter(|i| i % 7 == 0 || *i == size - 1).collect();
+            assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(set.count(), expected.len());
+            assert!(set.contains(size - 1));
+
+            set.remove(size - 1);
+            assert!(!set.contains(size - 1));
+            set.clear();
+            assert!(set.is_empty());
+        }
+    }
+
+    #[test]
+    fn set_operations() {
+        let from = |size, indices: &[usize]| {
+            let mut set = BitSet::empty(size);
+            for &i in indices {
+                set.insert(i);
+            }
+            set
+        };
+        let a = from(100, &[1, 5, 64, 70, 99]);
+        let b = from(100, &[5, 70, 80]);
+        assert_eq!(a.intersection_count(&b), 2);
+
+        let mut union = a.clone();
+        union.union_with(&b);
+        assert_eq!(union, from(100, &[1, 5, 64, 70, 80, 99]));
+        assert!(a.is_subset(&union) && b.is_subset(&union));
+        assert!(!union.is_subset(&a));
+
+        let mut both = a.clone();
+        assert!(both.intersect_with(&b));
+        assert_eq!(both, from(100, &[5, 70]));
+        assert!(!both.intersect_with(&b), "nothing left to remove");
+    }
+}
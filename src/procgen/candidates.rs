@@ -0,0 +1,101 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::procgen::wfc::{Wave, WaveTile};
+
+/// Weighted Shannon entropy of a tile's options, given their weights, with a little noise from
+/// `tiebreak`, as the bits of the float so candidates can be ordered in a heap. Options without
+/// weight don't add to the entropy
+pub fn entropy_priority(weights: impl Iterator<Item = f64>, tiebreak: u32) -> u64 {
+    let (sum, sum_w_log_w) = weights.fold((0.0, 0.0), |(sum, wlw), w| {
+        let wlw = if w > 0.0 { wlw + w * w.ln() } else { wlw };
+        (sum + w, wlw)
+    });
+    if sum <= 0.0 {
+        // nothing left to pick, surface the contradiction right away
+        return 0;
+    }
+
+    let entropy = (sum.ln() - sum_w_log_w / sum).max(0.0);
+    let noise = tiebreak as f64 / u32::MAX as f64 * 1e-6;
+
+    // non-negative floats order the same as their bit patterns
+    (entropy + noise).to_bits()
+}
+
+/// Unobserved tiles keyed by (priority, tiebreak, index), lowest first. Tiles get a fresh entry
+/// whenever their options change, and entries that went stale are skipped when popped
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    heap: BinaryHeap<Reverse<(u64, u32, usize)>>,
+
+    /// latest priority pushed for each tile
+    priorities: Vec<u64>,
+}
+
+impl Candidates {
+    pub fn new(num_tiles: usize) -> Self {
+        Self {
+            heap: BinaryHeap::with_capacity(num_tiles),
+            priorities: vec![0; num_tiles],
+        }
+    }
+
+    pub fn push(&mut self, idx: usize, priority: u64, tiebreak: u32) {
+        self.priorities[idx] = priority;
+        self.heap.push(Reverse((priority, tiebreak, idx)));
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+
+    /// Index of the unobserved tile to observe next
+    pub fn pop(&mut self, wave: &Wave) -> Option<usize> {
+        while let Some(Reverse((priority, _, idx))) = self.heap.pop() {
+            if matches!(wave.tiles[idx], WaveTile::Unobserved(_))
+                && self.priorities[idx] == priority
+            {
+                return Some(idx);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::bitset::BitSet;
+
+    #[test]
+    fn pops_lowest_priority_and_skips_stale_entries() {
+        let mut wave = Wave {
+            tiles: vec![WaveTile::Unobserved(BitSet::full(2)); 4],
+            width: 4,
+            height: 1,
+            periodic: false,
+        };
+        let mut candidates = Candidates::new(4);
+        candidates.push(0, 5, 0);
+        candidates.push(1, 3, 0);
+        candidates.push(2, 3, 1);
+        candidates.push(3, 4, 0);
+        // tile 0 changed, tile 1 got observed
+        candidates.push(0, 1, 0);
+        wave.tiles[1] = WaveTile::Observed(0);
+
+        let order: Vec<usize> = std::iter::from_fn(|| candidates.pop(&wave)).collect();
+        assert_eq!(order, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn entropy_grows_with_even_weights() {
+        let one = entropy_priority([2.0].into_iter(), 0);
+        let uneven = entropy_priority([9.0, 1.0].into_iter(), 0);
+        let even = entropy_priority([5.0, 5.0].into_iter(), 0);
+        assert!(one < uneven && uneven < even);
+        // weightless options don't count
+        assert_eq!(entropy_priority([2.0, 0.0].into_iter(), 0), one);
+        assert_eq!(entropy_priority(std::iter::empty(), 0), 0);
+    }
+}
@@ -0,0 +1,502 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{Context, Error, Result, bail};
+use serde::{Deserialize, Deserializer, Serialize, de};
+
+use crate::procgen::{
+    bitset::BitSet,
+    roads::road_options,
+    types::Tileset,
+    wfc::{Contradiction, Wave, WaveTile},
+};
+
+/// How strongly options still short of their minimum are favored when observing. The weight is
+/// scaled by `1 + MIN_COUNT_BOOST * needed * wave size / room`, where `room` is the number of
+/// tiles that could still take them, so rare tiles get placed before the wave fills up
+const MIN_COUNT_BOOST: f32 = 16.0;
+
+/// A number of wave tiles, either absolute or relative to the size of the wave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Tiles(usize),
+    Percent(f32),
+}
+
+impl Amount {
+    /// Number of tiles out of `total`, rounding percentages up for minimums and down for
+    /// maximums so that they are never loosened
+    fn resolve(self, total: usize, round_up: bool) -> usize {
+        match self {
+            Self::Tiles(tiles) => tiles,
+            Self::Percent(percent) => {
+                let tiles = percent / 100.0 * total as f32;
+                if round_up {
+                    tiles.ceil() as usize
+                } else {
+                    tiles.floor() as usize
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = Error;
+
+    /// Parses `12` or `5%`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent: f32 = percent.trim().parse()?;
+                if !(0.0..=100.0).contains(&percent) {
+                    bail!("percentage must be between 0 and 100, got {}", percent);
+                }
+                Ok(Self::Percent(percent))
+            }
+            None => Ok(Self::Tiles(s.parse()?)),
+        }
+    }
+}
+
+/// Config files give amounts as numbers or as strings like "5%"
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Tiles(usize),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Tiles(tiles) => Ok(Self::Tiles(tiles)),
+            Repr::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Bounds on how many wave tiles hold a base tile, in any orientation
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileCount {
+    pub tile: String,
+
+    #[serde(default)]
+    pub min: Option<Amount>,
+
+    #[serde(default)]
+    pub max: Option<Amount>,
+}
+
+impl FromStr for TileCount {
+    type Err = Error;
+
+    /// Parses `tile=amount` for an exact count, or `tile=min..max` where either bound can be
+    /// left out, e.g. `road_end=2` or `pure_space=..5%`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((tile, range)) = s.split_once('=') else {
+            bail!("expected tile=count or tile=min..max, got '{}'", s);
+        };
+        let bound = |amount: &str| -> Result<Option<Amount>> {
+            let amount = amount.trim();
+            if amount.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(amount.parse()?))
+        };
+        let (min, max) = match range.split_once("..") {
+            Some((min, max)) => (bound(min)?, bound(max)?),
+            None => {
+                let exact = bound(range)?;
+                (exact, exact)
+            }
+        };
+
+        Ok(TileCount {
+            tile: tile.trim().to_string(),
+            min,
+            max,
+        })
+    }
+}
+
+/// Global constraints on what the output contains as a whole. Checked after every propagation,
+/// which makes the last one the final check of the finished wave
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CountConstraints {
+    #[serde(default)]
+    pub tiles: Vec<TileCount>,
+
+    /// minimum number of wave tiles with road bits
+    #[serde(default)]
+    pub min_road_length: Option<usize>,
+}
+
+impl CountConstraints {
+    /// Reads constraints from a JSON file like
+    /// `{ "tiles": [{ "tile": "road_end", "min": 2, "max": 2 }], "min_road_length": 20 }`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read constraints file: {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("in {}", path.display()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.min_road_length.is_none()
+    }
+}
+
+/// A set of options whose number of wave tiles is bounded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Counter {
+    options: BitSet,
+    min: usize,
+    max: usize,
+}
+
+/// Number of wave tiles that hold a counter's options for sure, and that might
+#[derive(Debug, Clone, Copy)]
+struct Tally {
+    must: usize,
+    may: usize,
+}
+
+/// Whether a wave tile holds a counter's options for sure, and whether it might
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Counted {
+    must: bool,
+    may: bool,
+}
+
+impl Counter {
+    fn counted(&self, tile: &WaveTile) -> Counted {
+        match tile {
+            WaveTile::Observed(option) => {
+                let counted = self.options.contains(*option);
+                Counted {
+                    must: counted,
+                    may: counted,
+                }
+            }
+            WaveTile::Unobserved(options) => {
+                let counted = options.intersection_count(&self.options);
+                Counted {
+                    must: counted == options.count(),
+                    may: counted > 0,
+                }
+            }
+        }
+    }
+}
+
+/// Running tallies of every counter, along with what each wave tile added to them
+#[derive(Debug, Clone)]
+struct TallyCache {
+    /// `counted[idx * counters + counter]` is what wave tile `idx` adds to `counter`
+    counted: Vec<Counted>,
+    tallies: Vec<Tally>,
+}
+
+/// [`CountConstraints`] resolved against a tileset and wave size
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountLimits {
+    counters: Vec<Counter>,
+
+    /// tallies of the wave as of the last [`Self::update`]. Built from the wave when next
+    /// needed if missing, e.g. after loading a saved state
+    #[serde(skip)]
+    cache: Option<TallyCache>,
+}
+
+impl CountLimits {
+    /// Errors if a constraint names a tile that isn't in the tileset or can't be met in a wave
+    /// with `num_tiles` tiles
+    pub fn new(
+        tileset: &Tileset,
+        constraints: &CountConstraints,
+        num_tiles: usize,
+    ) -> Result<Self> {
+        let mut counters = Vec::new();
+        for count in &constraints.tiles {
+            let base_idx = tileset
+                .tile_names
+                .iter()
+                .position(|name| *name == count.tile)
+                .with_context(|| format!("tile '{}' not in tileset", count.tile))?;
+            let mut options = BitSet::empty(tileset.variants.len());
+            for (option, variant) in tileset.variants.iter().enumerate() {
+                if variant.base_tile_idx == base_idx {
+                    options.insert(option);
+                }
+            }
+
+            let min = count.min.map_or(0, |min| min.resolve(num_tiles, true));
+            let max = count
+                .max
+                .map_or(num_tiles, |max| max.resolve(num_tiles, false));
+            if min > max || min > num_tiles {
+                bail!(
+                    "can't have {} to {} '{}' tiles in a wave of {} tiles",
+                    min,
+                    max,
+                    count.tile,
+                    num_tiles
+                );
+            }
+            counters.push(Counter { options, min, max });
+        }
+
+        if let Some(length) = constraints.min_road_length {
+            if length > num_tiles {
+                bail!(
+                    "road can't be {} tiles long in a wave of {} tiles",
+                    length,
+                    num_tiles
+                );
+            }
+            counters.push(Counter {
+                options: road_options(tileset),
+                min: length,
+                max: num_tiles,
+            });
+        }
+
+        Ok(Self {
+            counters,
+            cache: None,
+        })
+    }
+
+    /// Takes note that the options of wave tile `idx` changed. Every change has to be reported
+    /// before the counts are next checked
+    pub fn update(&mut self, wave: &Wave, idx: usize) {
+        let Some(cache) = &mut self.cache else {
+            return;
+        };
+        let start = idx * self.counters.len();
+        for (counter, (counted, tally)) in self.counters.iter().zip(
+            cache.counted[start..start + self.counters.len()]
+                .iter_mut()
+                .zip(&mut cache.tallies),
+        ) {
+            let new = counter.counted(&wave.tiles[idx]);
+            tally.must = tally.must + new.must as usize - counted.must as usize;
+            tally.may = tally.may + new.may as usize - counted.may as usize;
+            *counted = new;
+        }
+    }
+
+    /// Tallies of every counter, counted from the wave if they aren't kept yet
+    fn tallies(&mut self, wave: &Wave) -> &[Tally] {
+        let counters = &self.counters;
+        let cache = self.cache.get_or_insert_with(|| {
+            let counted: Vec<Counted> = wave
+                .tiles
+                .iter()
+                .flat_map(|tile| counters.iter().map(|counter| counter.counted(tile)))
+                .collect();
+            let mut tallies = vec![Tally { must: 0, may: 0 }; counters.len()];
+            for (i, counted) in counted.iter().enumerate() {
+                let tally = &mut tallies[i % counters.len()];
+                tally.must += counted.must as usize;
+                tally.may += counted.may as usize;
+            }
+            TallyCache { counted, tallies }
+        });
+        &cache.tallies
+    }
+
+    /// Multipliers for the weights of `options` when observing a tile: options short of a
+    /// minimum are favored, and options whose maximum is close are avoided in proportion to
+    /// how many tiles could still take them
+    pub fn weight_factors(&mut self, wave: &Wave, options: &[usize]) -> Vec<f32> {
+        let mut factors = vec![1.0; options.len()];
+        self.tallies(wave);
+        let tallies = &self
+            .cache
+            .as_ref()
+            .expect("tallies were just counted")
+            .tallies;
+        for (counter, tally) in self.counters.iter().zip(tallies) {
+            // tiles that could still go either way
+            let room = (tally.may - tally.must).max(1) as f32;
+            let needed = counter.min.saturating_sub(tally.must) as f32;
+            let spare = counter.max.saturating_sub(tally.must) as f32;
+
+            let factor = (1.0 + MIN_COUNT_BOOST * needed * wave.tiles.len() as f32 / room)
+                * (spare / room).min(1.0);
+            for (option, f) in options.iter().zip(&mut factors) {
+                if counter.options.contains(*option) {
+                    *f *= factor;
+                }
+            }
+        }
+        factors
+    }
+
+    /// Checks every count against the wave. Returns the options that have to go so the counts
+    /// can still be met as (tile index, option): once a maximum is reached the counted options
+    /// are removed everywhere else, and once only just enough tiles are left for a minimum
+    /// they're forced to take the counted options. `(x, y)` is the tile that last changed,
+    /// blamed if a count can't be met anymore
+    pub fn prune(
+        &mut self,
+        wave: &Wave,
+        x: usize,
+        y: usize,
+    ) -> Result<Vec<(usize, usize)>, Contradiction> {
+        let mut removals = Vec::new();
+        self.tallies(wave);
+        let tallies = &self
+            .cache
+            .as_ref()
+            .expect("tallies were just counted")
+            .tallies;
+        for (counter, tally) in self.counters.iter().zip(tallies) {
+            if tally.must > counter.max || tally.may < counter.min {
+                return Err(Contradiction {
+                    x,
+                    y,
+                    neighbor: None,
+                });
+            }
+
+            let at_max = tally.must == counter.max;
+            let at_min = tally.may == counter.min;
+            if !(at_max || at_min) || tally.may == tally.must {
+                continue;
+            }
+            for (idx, tile) in wave.tiles.iter().enumerate() {
+                let WaveTile::Unobserved(options) = tile else {
+                    continue;
+                };
+                let counted = options.intersection_count(&counter.options);
+                if counted == 0 || counted == options.count() {
+                    continue;
+                }
+                // at the max, drop the counted options. At the min, keep only them
+                removals.extend(
+                    options
+                        .iter()
+                        .filter(|option| counter.options.contains(*option) == at_max)
+                        .map(|option| (idx, option)),
+                );
+            }
+            if !removals.is_empty() {
+                // the other counts have to be checked against the smaller wave anyway
+                break;
+            }
+        }
+        Ok(removals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::{
+        BacktrackConfig, Generator, WfcConfig, tileset::make_island_race_tileset,
+    };
+
+    #[test]
+    fn parses_counts() {
+        let count: TileCount = "road_end=2".parse().unwrap();
+        assert_eq!(count.min, Some(Amount::Tiles(2)));
+        assert_eq!(count.max, Some(Amount::Tiles(2)));
+
+        let count: TileCount = "pure_space=..5%".parse().unwrap();
+        assert_eq!((count.min, count.max), (None, Some(Amount::Percent(5.0))));
+
+        let constraints: CountConstraints = serde_json::from_str(
+            r#"{ "tiles": [{ "tile": "pure_grass", "min": "10%" }], "min_road_length": 8 }"#,
+        )
+        .unwrap();
+        assert_eq!(constraints.tiles[0].min, Some(Amount::Percent(10.0)));
+        assert!("road_end".parse::<TileCount>().is_err());
+    }
+
+    #[test]
+    fn output_meets_counts() {
+        let tileset = make_island_race_tileset();
+        let base_names: Vec<String> = tileset
+            .variants
+            .iter()
+            .map(|variant| tileset.tile_names[variant.base_tile_idx].clone())
+            .collect();
+        let counts = CountConstraints {
+            tiles: vec![
+                "road_end=2".parse().unwrap(),
+                "pure_space=..20%".parse().unwrap(),
+            ],
+            min_road_length: Some(6),
+        };
+        let generator = Generator {
+            tileset,
+            width: 10,
+            height: 10,
+            config: WfcConfig {
+                backtracking: Some(BacktrackConfig::default()),
+                counts,
+                ..WfcConfig::default()
+            },
+            max_attempts: 10,
+        };
+        let (wfc, _) = generator.run(0, &mut ()).unwrap();
+
+        let count = |name: &str| {
+            wfc.wave
+                .tiles
+                .iter()
+                .filter(|tile| matches!(tile, WaveTile::Observed(option) if base_names[*option] == name))
+                .count()
+        };
+        assert_eq!(count("road_end"), 2);
+        assert!(count("pure_space") <= 20);
+        assert!(count("road_straight") + count("road_turn") + count("road_end") >= 6);
+    }
+
+    #[test]
+    fn running_tallies_match_counting_from_scratch() {
+        let tileset = make_island_race_tileset();
+        let counts = CountConstraints {
+            tiles: vec!["road_end=..3".parse().unwrap()],
+            min_road_length: Some(4),
+        };
+        let num_options = tileset.variants.len();
+        let mut wave = Wave {
+            tiles: vec![WaveTile::Unobserved(BitSet::full(num_options)); 9],
+            width: 3,
+            height: 3,
+            periodic: false,
+        };
+        let mut limits = CountLimits::new(&tileset, &counts, 9).unwrap();
+        limits.tallies(&wave);
+
+        let road = road_options(&tileset);
+        let grass = (0..num_options)
+            .find(|option| !road.contains(*option))
+            .unwrap();
+        wave.tiles[0] = WaveTile::Observed(road.iter().next().unwrap());
+        wave.tiles[4] = WaveTile::Unobserved(road.clone());
+        wave.tiles[8] = WaveTile::Observed(grass);
+        // undone again
+        wave.tiles[4] = WaveTile::Unobserved(BitSet::full(num_options));
+        wave.tiles[5] = WaveTile::Unobserved(road);
+        for idx in [0, 4, 8, 5] {
+            limits.update(&wave, idx);
+        }
+
+        let mut fresh = CountLimits::new(&tileset, &counts, 9).unwrap();
+        let tallies = |limits: &mut CountLimits| -> Vec<(usize, usize)> {
+            limits
+                .tallies(&wave)
+                .iter()
+                .map(|tally| (tally.must, tally.may))
+                .collect()
+        };
+        assert_eq!(tallies(&mut limits), tallies(&mut fresh));
+        assert_eq!(tallies(&mut limits)[1], (2, 8));
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::Error;
+
+use crate::procgen::{
+    observer::WfcObserver,
+    types::Tileset,
+    wfc::{Bitmap, Contradiction, Region, WaveFunctionCollapse, WfcConfig},
+};
+
+/// A generation attempt that ran into a contradiction
+#[derive(Debug, Clone, Copy)]
+pub struct AttemptFailure {
+    pub seed: u64,
+
+    /// step at which the contradiction happened
+    pub step: usize,
+
+    pub contradiction: Contradiction,
+}
+
+#[derive(Debug, Clone)]
+pub struct GenerationReport {
+    /// seed of the attempt that finished
+    pub seed: u64,
+
+    /// total number of attempts, including the one that finished
+    pub attempts: usize,
+
+    pub failures: Vec<AttemptFailure>,
+}
+
+/// Seed used for the given attempt. The first attempt uses the seed as is
+pub fn derive_seed(seed: u64, attempt: usize) -> u64 {
+    if attempt == 0 {
+        return seed;
+    }
+
+    // splitmix64 so nearby seeds/attempts don't produce correlated runs
+    let mut z = seed.wrapping_add((attempt as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Restart-on-contradiction driver around [`WaveFunctionCollapse`]
+pub struct Generator {
+    pub tileset: Tileset,
+    pub width: usize,
+    pub height: usize,
+    pub config: WfcConfig,
+
+    /// number of attempts before giving up
+    pub max_attempts: usize,
+}
+
+impl Generator {
+    /// Runs WFC until an attempt finishes without a contradiction, restarting with a derived
+    /// seed after each failure. `observer` follows every attempt. Errors if all attempts fail
+    pub fn run(
+        &self,
+        seed: u64,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(WaveFunctionCollapse, GenerationReport), Error> {
+        self.run_wfc_attempts(seed, observer, |attempt_seed| {
+            WaveFunctionCollapse::new(
+                self.tileset.clone(),
+                self.width,
+                self.height,
+                attempt_seed,
+                self.config.clone(),
+            )
+        })
+    }
+
+    /// Like [`Self::run`], but only generates the wave tiles in `region` and keeps the rest of
+    /// `bitmap` (see [`WaveFunctionCollapse::regenerate`]). Errors if the bitmap isn't
+    /// `width` x `height` tiles
+    pub fn regenerate(
+        &self,
+        bitmap: &Bitmap,
+        region: Region,
+        seed: u64,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(WaveFunctionCollapse, GenerationReport), Error> {
+        let tile_size = self.tileset.tile_size;
+        if (bitmap.width, bitmap.height) != (self.width * tile_size, self.height * tile_size) {
+            return Err(anyhow::anyhow!(
+                "bitmap is {}x{} bits, expected {}x{} tiles of {} bits",
+                bitmap.width,
+                bitmap.height,
+                self.width,
+                self.height,
+                tile_size
+            ));
+        }
+
+        self.run_wfc_attempts(seed, observer, |attempt_seed| {
+            WaveFunctionCollapse::regenerate(
+                self.tileset.clone(),
+                bitmap,
+                region,
+                attempt_seed,
+                self.config.clone(),
+            )
+        })
+    }
+
+    /// Attempts shared by [`Self::run`] and [`Self::regenerate`]. `make_wfc` sets up the wave
+    /// for an attempt's seed
+    fn run_wfc_attempts(
+        &self,
+        seed: u64,
+        observer: &mut dyn WfcObserver,
+        make_wfc: impl Fn(u64) -> Result<WaveFunctionCollapse, Error>,
+    ) -> Result<(WaveFunctionCollapse, GenerationReport), Error> {
+        run_attempts(seed, self.max_attempts, |attempt_seed| {
+            let mut wfc = match make_wfc(attempt_seed) {
+                Ok(wfc) => wfc,
+                Err(e) => {
+                    // pins with a random rotation might fit with another seed
+                    let contradiction = e.downcast::<Contradiction>()?;
+                    return Ok(Err(AttemptFailure {
+                        seed: attempt_seed,
+                        step: 0,
+                        contradiction,
+                    }));
+                }
+            };
+            Ok(match wfc.step_all(observer) {
+                Ok(()) => Ok(wfc),
+                Err(contradiction) => Err(AttemptFailure {
+                    seed: attempt_seed,
+                    step: wfc.steps(),
+                    contradiction,
+                }),
+            })
+        })
+    }
+}
+
+/// Restart loop shared by every model. `attempt` runs a whole attempt with the given seed and
+/// returns what it generated, or the failure to record before trying again with a derived seed.
+/// Errors from `attempt` itself (not contradictions) end the loop right away. Errors if all
+/// attempts fail
+pub fn run_attempts<T>(
+    seed: u64,
+    max_attempts: usize,
+    mut attempt: impl FnMut(u64) -> Result<Result<T, AttemptFailure>, Error>,
+) -> Result<(T, GenerationReport), Error> {
+    let mut failures = Vec::new();
+
+    for attempt_idx in 0..max_attempts {
+        let attempt_seed = derive_seed(seed, attempt_idx);
+        match attempt(attempt_seed)? {
+            Ok(output) => {
+                let report = GenerationReport {
+                    seed: attempt_seed,
+                    attempts: attempt_idx + 1,
+                    failures,
+                };
+                return Ok((output, report));
+            }
+            Err(failure) => failures.push(failure),
+        }
+    }
+
+    let last = failures
+        .last()
+        .map(|f| format!(", last: {}", f.contradiction))
+        .unwrap_or_default();
+    Err(anyhow::anyhow!(
+        "WFC ran into a contradiction in all {} attempts (failed at steps {:?}{})",
+        max_attempts,
+        failures.iter().map(|f| f.step).collect::<Vec<_>>(),
+        last
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::{
+        roads::RoadConstraint,
+        tileset::{island_race_pins, make_island_race_tileset},
+        wfc::WaveTile,
+    };
+
+    fn generator(width: usize, height: usize) -> Generator {
+        Generator {
+            tileset: make_island_race_tileset(),
+            width,
+            height,
+            config: WfcConfig {
+                pins: island_race_pins(width, height, None),
+                ..WfcConfig::default()
+            },
+            max_attempts: 10,
+        }
+    }
+
+    #[test]
+    fn generates_non_square_waves() {
+        for (width, height) in [(9, 3), (2, 7), (1, 1)] {
+            let (wfc, _) = generator(width, height).run(0, &mut ()).unwrap();
+            assert_eq!((wfc.wave.width, wfc.wave.height), (width, height));
+            assert!(
+                wfc.wave
+                    .tiles
+                    .iter()
+                    .all(|tile| matches!(tile, WaveTile::Observed(_)))
+            );
+
+            let bitmap = wfc.bitmap();
+            assert_eq!((bitmap.width, bitmap.height), (width * 4, height * 4));
+            assert_eq!(bitmap.bits.len(), width * height * 16);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_waves() {
+        for (width, height) in [(0, 0), (0, 5), (5, 0)] {
+            let Err(err) = generator(width, height).run(0, &mut ()) else {
+                panic!("{}x{} wave was generated", width, height);
+            };
+            assert!(err.to_string().contains("empty"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn derived_seeds_start_with_the_seed() {
+        assert_eq!(derive_seed(42, 0), 42);
+        let seeds: Vec<u64> = (0..100).map(|attempt| derive_seed(42, attempt)).collect();
+        for (i, seed) in seeds.iter().enumerate() {
+            assert!(
+                !seeds[i + 1..].contains(seed),
+                "attempt {} repeats a seed",
+                i
+            );
+        }
+        assert_ne!(derive_seed(42, 1), derive_seed(43, 1));
+    }
+
+    #[test]
+    fn restarts_record_every_failure() {
+        // closed road loops on a small wave without backtracking fail with most seeds
+        let mut generator = generator(10, 10);
+        generator.config.pins = island_race_pins(10, 10, Some(RoadConstraint::Loop));
+        generator.config.roads = Some(RoadConstraint::Loop);
+        generator.max_attempts = 100;
+
+        let (_, report) = generator.run(5, &mut ()).unwrap();
+        assert!(report.attempts > 1, "the first attempt with seed 5 fails");
+        assert_eq!(report.failures.len(), report.attempts - 1);
+        assert_eq!(report.seed, derive_seed(5, report.attempts - 1));
+        for (attempt, failure) in report.failures.iter().enumerate() {
+            assert_eq!(failure.seed, derive_seed(5, attempt));
+            assert!(failure.step > 0);
+        }
+
+        generator.max_attempts = 1;
+        let Err(err) = generator.run(5, &mut ()) else {
+            panic!("the only attempt succeeded");
+        };
+        assert!(err.to_string().contains("all 1 attempts"), "{}", err);
+    }
+
+    #[test]
+    fn errors_other_than_contradictions_end_the_attempts() {
+        let mut calls = 0;
+        let result: Result<((), GenerationReport), Error> = run_attempts(0, 10, |_| {
+            calls += 1;
+            Err(anyhow::anyhow!("broken"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
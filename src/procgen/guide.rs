@@ -0,0 +1,263 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+use crate::procgen::{
+    bitset::BitSet,
+    roads::road_options,
+    types::Tileset,
+    wfc::{Wave, WaveTile},
+};
+
+/// What a guide color does to the wave tiles it covers. An empty rule leaves them alone
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuideRule {
+    /// only these base tiles are allowed (any tile if empty)
+    #[serde(default)]
+    pub tiles: Vec<String>,
+
+    /// these base tiles are not allowed
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// only tiles with road bits are allowed, so the road passes through
+    #[serde(default)]
+    pub road: bool,
+
+    /// multipliers for the weights of base tiles
+    #[serde(default)]
+    pub weights: HashMap<String, f32>,
+}
+
+/// A painted image steering generation. The image is stretched over the wave, so a pixel covers
+/// one or more wave tiles, and the legend says what each color means. Fully transparent pixels
+/// don't constrain anything
+#[derive(Debug, Clone)]
+pub struct Guide {
+    pub image: RgbaImage,
+    pub legend: HashMap<[u8; 3], GuideRule>,
+}
+
+/// Parses `#rrggbb`
+fn parse_hex_color(color: &str) -> Result<[u8; 3]> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.is_ascii() {
+        bail!("expected a color like #00ff00, got '{}'", color);
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .with_context(|| format!("expected a color like #00ff00, got '{}'", color))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl Guide {
+    /// Loads the guide image and a JSON legend mapping colors to [`GuideRule`]s, like
+    /// `{ "#0000ff": { "tiles": ["pure_space"] }, "#ff0000": { "road": true } }`
+    pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(image_path: P, legend_path: Q) -> Result<Self> {
+        let (image_path, legend_path) = (image_path.as_ref(), legend_path.as_ref());
+        let image = image::open(image_path)
+            .with_context(|| format!("Failed to load guide image: {}", image_path.display()))?
+            .to_rgba8();
+
+        let json = fs::read_to_string(legend_path)
+            .with_context(|| format!("Failed to read guide legend: {}", legend_path.display()))?;
+        let rules: HashMap<String, GuideRule> =
+            serde_json::from_str(&json).with_context(|| format!("in {}", legend_path.display()))?;
+        let legend = rules
+            .into_iter()
+            .map(|(color, rule)| Ok((parse_hex_color(&color)?, rule)))
+            .collect::<Result<_>>()
+            .with_context(|| format!("in {}", legend_path.display()))?;
+
+        Ok(Self { image, legend })
+    }
+}
+
+/// A rule resolved against a tileset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResolvedRule {
+    allowed: BitSet,
+
+    /// per option
+    weights: Vec<f32>,
+}
+
+/// [`Guide`] resolved against a tileset and wave size
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuideMask {
+    /// index into `rules` for every wave tile
+    cells: Vec<Option<usize>>,
+    rules: Vec<ResolvedRule>,
+}
+
+impl GuideMask {
+    /// Errors if the guide uses colors missing from its legend or the legend names tiles that
+    /// aren't in the tileset
+    pub fn new(guide: &Guide, tileset: &Tileset, width: usize, height: usize) -> Result<Self> {
+        let num_options = tileset.variants.len();
+        let options_of = |name: &str| -> Result<Vec<usize>> {
+            let base_idx = tileset
+                .tile_names
+                .iter()
+                .position(|n| n == name)
+                .with_context(|| format!("guide legend uses tile '{}', not in tileset", name))?;
+            Ok((0..num_options)
+                .filter(|&option| tileset.variants[option].base_tile_idx == base_idx)
+                .collect())
+        };
+
+        let mut rule_idxs = HashMap::new();
+        let mut rules = Vec::new();
+        for (color, rule) in &guide.legend {
+            let mut allowed = if rule.tiles.is_empty() {
+                BitSet::full(num_options)
+            } else {
+                BitSet::empty(num_options)
+            };
+            for name in &rule.tiles {
+                for option in options_of(name)? {
+                    allowed.insert(option);
+                }
+            }
+            for name in &rule.exclude {
+                for option in options_of(name)? {
+                    allowed.remove(option);
+                }
+            }
+            if rule.road {
+                allowed.intersect_with(&road_options(tileset));
+            }
+
+            let mut weights = vec![1.0; num_options];
+            for (name, factor) in &rule.weights {
+                if !(factor.is_finite() && *factor >= 0.0) {
+                    bail!("guide legend weight for '{}' must be at least 0", name);
+                }
+                for option in options_of(name)? {
+                    weights[option] *= factor;
+                }
+            }
+
+            rule_idxs.insert(*color, rules.len());
+            rules.push(ResolvedRule { allowed, weights });
+        }
+
+        let (img_width, img_height) = guide.image.dimensions();
+        let mut cells = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let px = (x * img_width as usize / width) as u32;
+                let py = (y * img_height as usize / height) as u32;
+                let [r, g, b, a] = guide.image.get_pixel(px, py).0;
+                if a == 0 {
+                    cells.push(None);
+                    continue;
+                }
+                let Some(&rule) = rule_idxs.get(&[r, g, b]) else {
+                    bail!(
+                        "guide pixel ({}, {}) is #{:02x}{:02x}{:02x}, which isn't in the legend",
+                        px,
+                        py,
+                        r,
+                        g,
+                        b
+                    );
+                };
+                cells.push(Some(rule));
+            }
+        }
+
+        Ok(Self { cells, rules })
+    }
+
+    /// Removes the options the guide doesn't allow from every unobserved tile. Errors if that
+    /// leaves a tile without options, since no seed can fix that
+    pub fn restrict(&self, wave: &mut Wave) -> Result<()> {
+        for (idx, rule) in self.cells.iter().enumerate() {
+            let (Some(rule), WaveTile::Unobserved(options)) = (rule, &mut wave.tiles[idx]) else {
+                continue;
+            };
+            options.intersect_with(&self.rules[*rule].allowed);
+            if options.is_empty() {
+                bail!(
+                    "guide leaves no tiles for wave tile ({}, {})",
+                    idx % wave.width,
+                    idx / wave.width
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Multiplier for the weight of `option` at wave tile `idx`
+    pub fn weight_factor(&self, idx: usize, option: usize) -> f32 {
+        self.cells[idx].map_or(1.0, |rule| self.rules[rule].weights[option])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::procgen::{Generator, WfcConfig, tileset::make_island_race_tileset};
+
+    #[test]
+    fn guide_restricts_wave_tiles() {
+        // 2x1 guide over a 6x4 wave: space on the left, no space on the right
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([0, 0, 255, 255]));
+        image.put_pixel(1, 0, Rgba([0, 255, 0, 255]));
+        let legend = HashMap::from([
+            (
+                parse_hex_color("#0000ff").unwrap(),
+                GuideRule {
+                    tiles: vec!["pure_space".to_string()],
+                    ..GuideRule::default()
+                },
+            ),
+            (
+                parse_hex_color("00FF00").unwrap(),
+                GuideRule {
+                    exclude: vec!["pure_space".to_string(), "island_corner".to_string()],
+                    weights: HashMap::from([("road_straight".to_string(), 0.0)]),
+                    ..GuideRule::default()
+                },
+            ),
+        ]);
+
+        let tileset = make_island_race_tileset();
+        let base_names: Vec<String> = tileset
+            .variants
+            .iter()
+            .map(|variant| tileset.tile_names[variant.base_tile_idx].clone())
+            .collect();
+        let generator = Generator {
+            tileset,
+            width: 6,
+            height: 4,
+            config: WfcConfig {
+                guide: Some(Guide { image, legend }),
+                ..WfcConfig::default()
+            },
+            max_attempts: 10,
+        };
+        let (wfc, _) = generator.run(0, &mut ()).unwrap();
+
+        for (idx, tile) in wfc.wave.tiles.iter().enumerate() {
+            let WaveTile::Observed(option) = tile else {
+                panic!("wave tile {} wasn't observed", idx);
+            };
+            let name = &base_names[*option];
+            if idx % 6 < 3 {
+                assert_eq!(name, "pure_space");
+            } else {
+                assert!(!["pure_space", "island_corner", "road_straight"].contains(&name.as_str()));
+            }
+        }
+    }
+}
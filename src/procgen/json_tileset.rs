@@ -0,0 +1,507 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::procgen::{
+    material::{Material, MaterialId, MaterialRegistry},
+    tileset::{SocketMirrors, TileSockets, edge_matched_tileset, socket_matched_tileset},
+    types::{BaseTile, Symmetry, TileBitmap, Tileset},
+};
+
+/// Bitmap tileset file. Tiles are drawn as character grids, and the legend says which material
+/// each character stands for. Materials besides the built-in ones can be added under
+/// `materials`, where `"road": true` makes a material count as road for the road constraints:
+///
+/// ```json
+/// {
+///     "materials": [{ "name": "lava", "color": [1.0, 0.2, 0.0, 1.0], "flat": true }],
+///     "legend": { "R": "road", "G": "grass", "L": "lava" },
+///     "tiles": [
+///         { "name": "road_straight", "symmetry": "I", "weight": 2.0,
+///           "bitmap": ["GRRG", "GRRG", "GRRG", "GRRG"] }
+///     ]
+/// }
+/// ```
+///
+/// Tiles can be any size, as long as they are square and all the same size. A tile's
+/// `symmetry` (X if left out) has to match its bitmap in the base orientation of the symmetry,
+/// e.g. L tiles are symmetric about the diagonal from bottom left to top right. Adjacency isn't
+/// listed: tiles may touch wherever their edges have the same bits. Alternatively, every tile
+/// can name the socket on each of its sides, and tiles may touch wherever their sockets connect
+/// (see [`SocketMirrors`]), no matter what the bits look like. The sockets have to have the
+/// tile's symmetry too:
+///
+/// ```json
+/// {
+///     "socket_mirrors": [["road_L", "road_R"]],
+///     "tiles": [
+///         { "name": "road_straight", "symmetry": "I", "bitmap": ["GRRG", ...],
+///           "sockets": { "top": "road", "left": "grass", "bottom": "road", "right": "grass" } }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitmapTilesetDef {
+    #[serde(default)]
+    materials: Vec<Material>,
+    /// pairs of asymmetric socket labels that connect to each other
+    #[serde(default)]
+    socket_mirrors: Vec<(String, String)>,
+    /// material names
+    legend: HashMap<char, String>,
+    tiles: Vec<BitmapTileDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitmapTileDef {
+    name: String,
+    #[serde(default = "default_symmetry")]
+    symmetry: String,
+    #[serde(default = "default_weight")]
+    weight: f32,
+    /// one string per row, top to bottom
+    bitmap: Vec<String>,
+    sockets: Option<SocketsDef>,
+}
+
+/// Socket labels of a tile in its base orientation, each read counterclockwise around the tile
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SocketsDef {
+    top: String,
+    left: String,
+    bottom: String,
+    right: String,
+}
+
+impl From<SocketsDef> for TileSockets {
+    fn from(def: SocketsDef) -> Self {
+        [def.top, def.left, def.bottom, def.right]
+    }
+}
+
+fn default_symmetry() -> String {
+    "X".to_string()
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// Parses `size` rows of `size` characters each
+fn parse_bitmap(
+    rows: &[String],
+    legend: &HashMap<char, MaterialId>,
+    size: usize,
+) -> Result<TileBitmap> {
+    if rows.len() != size {
+        bail!("bitmap has {} rows, expected {}", rows.len(), size);
+    }
+
+    let mut bitmap = Vec::with_capacity(size);
+    for (y, row) in rows.iter().enumerate() {
+        let bits =
+            row.chars()
+                .map(|c| {
+                    legend.get(&c).copied().with_context(|| {
+                        format!("row {} uses '{}', which isn't in the legend", y, c)
+                    })
+                })
+                .collect::<Result<Vec<MaterialId>>>()?;
+        if bits.len() != size {
+            bail!(
+                "row {} (\"{}\") has {} characters, expected {}",
+                y,
+                row,
+                bits.len(),
+                size
+            );
+        }
+        bitmap.push(bits);
+    }
+    Ok(bitmap)
+}
+
+/// Whether `bitmap` looks the same in the orientations `symmetry` treats as the same. With
+/// `exact`, it also has to look different in the orientations it tells apart
+fn has_symmetry(bitmap: &TileBitmap, symmetry: Symmetry, exact: bool) -> bool {
+    let tile = BaseTile {
+        bitmap: bitmap.clone(),
+        symmetry,
+        img: None,
+        orientation_imgs: Vec::new(),
+    };
+    let orientations: Vec<TileBitmap> = symmetry
+        .transforms()
+        .into_iter()
+        .map(|(rotation, reflected)| tile.transformed_bitmap(rotation, reflected))
+        .collect();
+
+    let same = [false, true].into_iter().all(|reflected| {
+        (0..4).all(|rotation| {
+            tile.transformed_bitmap(rotation, reflected)
+                == orientations[symmetry.orientation(rotation, reflected)]
+        })
+    });
+    let distinct = orientations
+        .iter()
+        .enumerate()
+        .all(|(i, a)| orientations[i + 1..].iter().all(|b| a != b));
+    same && (distinct || !exact)
+}
+
+/// Errors if the tile's bitmap doesn't have its declared symmetry, naming the symmetry it does
+/// have if there is one. Sockets can tell apart orientations that look the same, so with
+/// `sockets` the bitmap may be more symmetric than declared, but the sockets themselves have to
+/// stay the same in every orientation the symmetry treats as the same
+fn check_symmetry(tile: &BaseTile, sockets: Option<(&TileSockets, &SocketMirrors)>) -> Result<()> {
+    let all = [
+        Symmetry::X,
+        Symmetry::I,
+        Symmetry::Backslash,
+        Symmetry::L,
+        Symmetry::T,
+        Symmetry::F,
+    ];
+    if !has_symmetry(&tile.bitmap, tile.symmetry, sockets.is_none()) {
+        match all
+            .into_iter()
+            .find(|&symmetry| has_symmetry(&tile.bitmap, symmetry, true))
+        {
+            Some(actual) => bail!(
+                "declared symmetry {} doesn't match the bitmap, which has symmetry {}",
+                tile.symmetry,
+                actual
+            ),
+            // e.g. an L tile with the wrong diagonal as its axis
+            None => bail!(
+                "declared symmetry {} doesn't match the bitmap, which may need to be turned",
+                tile.symmetry
+            ),
+        }
+    }
+
+    let Some((sockets, mirrors)) = sockets else {
+        return Ok(());
+    };
+    if mirrors.has_symmetry(sockets, tile.symmetry) {
+        return Ok(());
+    }
+    // the most symmetric one that fits both, F always does
+    let fitting = all.into_iter().find(|&symmetry| {
+        has_symmetry(&tile.bitmap, symmetry, false) && mirrors.has_symmetry(sockets, symmetry)
+    });
+    match fitting {
+        Some(actual) if actual != Symmetry::F => bail!(
+            "declared symmetry {} doesn't match the sockets, which have symmetry {}",
+            tile.symmetry,
+            actual
+        ),
+        _ => bail!(
+            "declared symmetry {} doesn't match the sockets, which may need to be turned \
+             (or declare symmetry F)",
+            tile.symmetry
+        ),
+    }
+}
+
+/// Parse a bitmap tileset from a JSON string in the format described on `BitmapTilesetDef`
+pub fn parse_tileset_json_str(json: &str) -> Result<Tileset> {
+    let def: BitmapTilesetDef = serde_json::from_str(json).context("invalid tileset JSON")?;
+    // the first tile sets the size for the rest
+    let Some(tile_size) = def.tiles.first().map(|tile| tile.bitmap.len()) else {
+        bail!("tileset has no tiles");
+    };
+    if tile_size == 0 {
+        bail!("tile '{}' has an empty bitmap", def.tiles[0].name);
+    }
+
+    let mut materials = MaterialRegistry::default();
+    for material in def.materials {
+        materials.add(material)?;
+    }
+    let legend = def
+        .legend
+        .iter()
+        .map(|(c, name)| match materials.find(name) {
+            Some(id) => Ok((*c, id)),
+            None => bail!("legend maps '{}' to unknown material '{}'", c, name),
+        })
+        .collect::<Result<HashMap<char, MaterialId>>>()?;
+
+    // sockets replace edge matching for the whole tileset, so it's all or nothing
+    let uses_sockets = def.tiles.iter().any(|tile| tile.sockets.is_some());
+    if let Some(tile) = def
+        .tiles
+        .iter()
+        .find(|tile| tile.sockets.is_none() && uses_sockets)
+    {
+        bail!(
+            "tile '{}' has no sockets, but other tiles do. Either every tile or none needs them",
+            tile.name
+        );
+    }
+    if !uses_sockets && !def.socket_mirrors.is_empty() {
+        bail!("socket_mirrors given, but no tile has sockets");
+    }
+    let mirrors = SocketMirrors::new(&def.socket_mirrors)?;
+
+    let mut seen = HashSet::new();
+    let mut tile_defs = Vec::with_capacity(def.tiles.len());
+    let mut sockets = Vec::new();
+    for tile_def in def.tiles {
+        if !seen.insert(tile_def.name.clone()) {
+            bail!("tile '{}' is defined more than once", tile_def.name);
+        }
+        if !(tile_def.weight.is_finite() && tile_def.weight > 0.0) {
+            bail!(
+                "tile '{}' has weight {}, but weights have to be positive",
+                tile_def.name,
+                tile_def.weight
+            );
+        }
+        let symmetry: Symmetry = tile_def
+            .symmetry
+            .parse()
+            .with_context(|| format!("tile '{}'", tile_def.name))?;
+        let bitmap = parse_bitmap(&tile_def.bitmap, &legend, tile_size)
+            .with_context(|| format!("tile '{}'", tile_def.name))?;
+        let tile = BaseTile {
+            bitmap,
+            symmetry,
+            img: None,
+            orientation_imgs: Vec::new(),
+        };
+        let tile_sockets = tile_def.sockets.map(TileSockets::from);
+        check_symmetry(&tile, tile_sockets.as_ref().map(|s| (s, &mirrors)))
+            .with_context(|| format!("tile '{}'", tile_def.name))?;
+        sockets.extend(tile_sockets);
+        tile_defs.push((tile_def.name, tile, tile_def.weight));
+    }
+
+    if uses_sockets {
+        return Ok(socket_matched_tileset(
+            tile_size, materials, tile_defs, sockets, &mirrors,
+        ));
+    }
+    Ok(edge_matched_tileset(tile_size, materials, tile_defs))
+}
+
+/// Parse a bitmap tileset from a JSON file
+pub fn parse_tileset_json<P: AsRef<Path>>(json_path: P) -> Result<Tileset> {
+    let json_path = json_path.as_ref();
+    let json = fs::read_to_string(json_path)
+        .with_context(|| format!("Failed to read tileset file: {}", json_path.display()))?;
+    parse_tileset_json_str(&json).with_context(|| format!("in {}", json_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::{Generator, WfcConfig};
+
+    #[test]
+    fn generates_with_8x8_tiles() {
+        let json = r#"{
+            "legend": { "R": "Road", "G": "Grass" },
+            "tiles": [
+                { "name": "grass", "bitmap": [
+                    "GGGGGGGG", "GGGGGGGG", "GGGGGGGG", "GGGGGGGG",
+                    "GGGGGGGG", "GGGGGGGG", "GGGGGGGG", "GGGGGGGG"] },
+                { "name": "road", "symmetry": "I", "bitmap": [
+                    "GGGRRGGG", "GGGRRGGG", "GGGRRGGG", "GGGRRGGG",
+                    "GGGRRGGG", "GGGRRGGG", "GGGRRGGG", "GGGRRGGG"] }
+            ]
+        }"#;
+        let tileset = parse_tileset_json_str(json).unwrap();
+        assert_eq!(tileset.tile_size, 8);
+
+        // grass, road, road rotated. Roads only continue into roads running the same way
+        let [grass, vertical, horizontal] = [0, 1, 2];
+        assert!(tileset.allowed_neighbors[vertical][0][vertical]);
+        assert!(!tileset.allowed_neighbors[vertical][0][grass]);
+        assert!(!tileset.allowed_neighbors[vertical][0][horizontal]);
+        assert!(tileset.allowed_neighbors[vertical][1][grass]);
+
+        let generator = Generator {
+            tileset,
+            width: 3,
+            height: 2,
+            config: WfcConfig::default(),
+            max_attempts: 1,
+        };
+        let (wfc, _) = generator.run(0, &mut ()).unwrap();
+        let bitmap = wfc.bitmap();
+        assert_eq!((bitmap.width, bitmap.height), (24, 16));
+        assert!(
+            bitmap
+                .bits
+                .iter()
+                .all(|bit| [MaterialId::ROAD, MaterialId::GRASS].contains(bit))
+        );
+    }
+
+    #[test]
+    fn sockets_override_bitmap_edges() {
+        // lanes only continue into lanes going the same way, and the fence looks like grass but
+        // doesn't connect to it. A lane mirrored across its length still goes the same way, so
+        // it's a T
+        let json = r#"{
+            "socket_mirrors": [["lane_in", "lane_out"]],
+            "legend": { "G": "Grass", "R": "Road" },
+            "tiles": [
+                { "name": "lane", "symmetry": "T", "bitmap": ["GGGG", "RRRR", "RRRR", "GGGG"],
+                  "sockets": { "top": "grass", "left": "lane_in", "bottom": "grass",
+                               "right": "lane_out" } },
+                { "name": "grass", "bitmap": ["GGGG", "GGGG", "GGGG", "GGGG"],
+                  "sockets": { "top": "grass", "left": "grass", "bottom": "grass",
+                               "right": "grass" } },
+                { "name": "fence", "bitmap": ["GGGG", "GGGG", "GGGG", "GGGG"],
+                  "sockets": { "top": "fence", "left": "fence", "bottom": "fence",
+                               "right": "fence" } }
+            ]
+        }"#;
+        let tileset = parse_tileset_json_str(json).unwrap();
+        // the lane's 4 orientations, each turned a quarter further counterclockwise
+        let [east, north, west, south, grass, fence] = [0, 1, 2, 3, 4, 5];
+        assert_eq!(tileset.variants.len(), 6);
+        assert!(tileset.allowed_neighbors[east][3][east]);
+        assert!(tileset.allowed_neighbors[west][1][west]);
+        assert!(!tileset.allowed_neighbors[east][3][west]);
+        assert!(tileset.allowed_neighbors[north][0][north]);
+        assert!(tileset.allowed_neighbors[south][2][south]);
+        assert!(!tileset.allowed_neighbors[north][0][south]);
+        assert!(tileset.allowed_neighbors[east][0][grass]);
+        assert!(!tileset.allowed_neighbors[east][0][north]);
+        assert!(tileset.allowed_neighbors[fence][0][fence]);
+        // the bits of these edges match, but their sockets don't
+        assert!(!tileset.allowed_neighbors[grass][0][fence]);
+        assert!(!tileset.allowed_neighbors[fence][3][grass]);
+    }
+
+    #[test]
+    fn rejects_partial_sockets() {
+        let json = r#"{
+            "legend": { "G": "Grass" },
+            "tiles": [
+                { "name": "a", "bitmap": ["G"],
+                  "sockets": { "top": "g", "left": "g", "bottom": "g", "right": "g" } },
+                { "name": "b", "bitmap": ["G"] }
+            ]
+        }"#;
+        let err = parse_tileset_json_str(json).unwrap_err();
+        assert!(format!("{:#}", err).contains("'b'"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_characters_missing_from_legend() {
+        let json = r#"{
+            "legend": { "G": "Grass" },
+            "tiles": [{ "name": "a", "bitmap": ["GGGG", "GGGG", "GGXG", "GGGG"] }]
+        }"#;
+        let err = parse_tileset_json_str(json).unwrap_err();
+        assert!(format!("{:#}", err).contains("'X'"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_weights_that_arent_positive() {
+        for weight in ["0", "-1.5", "1e40"] {
+            let json = format!(
+                r#"{{
+                    "legend": {{ "G": "Grass" }},
+                    "tiles": [{{ "name": "a", "weight": {}, "bitmap": ["GGGG", "GGGG", "GGGG", "GGGG"] }}]
+                }}"#,
+                weight
+            );
+            let err = parse_tileset_json_str(&json).unwrap_err();
+            assert!(format!("{:#}", err).contains("tile 'a'"), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_symmetry_the_bitmap_doesnt_have() {
+        // a road end, which isn't symmetric enough for the default X
+        let json = r#"{
+            "legend": { "R": "Road", "G": "Grass" },
+            "tiles": [{ "name": "end", "bitmap": ["GGGG", "GRRG", "GRRG", "GRRG"] }]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(
+            err.contains("'end'") && err.contains("symmetry T"),
+            "{}",
+            err
+        );
+
+        // a corner with its axis on the main diagonal, where L has it on the other one
+        let json = r#"{
+            "legend": { "R": "Road", "G": "Grass" },
+            "tiles": [{ "name": "turn", "symmetry": "L",
+                        "bitmap": ["GRRG", "RRRG", "RRRG", "GGGG"] }]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(err.contains("'turn'"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_sockets_without_the_declared_symmetry() {
+        // a lane going up: turning it around makes it go down, so it isn't an I
+        let json = r#"{
+            "socket_mirrors": [["lane_in", "lane_out"]],
+            "legend": { "G": "Grass", "R": "Road" },
+            "tiles": [
+                { "name": "lane", "symmetry": "I", "bitmap": ["GRRG", "GRRG", "GRRG", "GRRG"],
+                  "sockets": { "top": "lane_out", "left": "grass", "bottom": "lane_in",
+                               "right": "grass" } }
+            ]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(err.contains("'lane'") && err.contains("sockets"), "{}", err);
+
+        // a bitmap that looks the same all around doesn't help
+        let json = r#"{
+            "legend": { "G": "Grass" },
+            "tiles": [
+                { "name": "gate", "bitmap": ["GG", "GG"],
+                  "sockets": { "top": "gate", "left": "grass", "bottom": "grass",
+                               "right": "grass" } }
+            ]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(
+            err.contains("'gate'") && err.contains("symmetry T"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_tiles_of_different_sizes() {
+        let json = r#"{
+            "legend": { "G": "Grass" },
+            "tiles": [
+                { "name": "small", "bitmap": ["GG", "GG"] },
+                { "name": "large", "bitmap": ["GGG", "GGG", "GGG"] }
+            ]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(
+            err.contains("'large'") && err.contains("expected 2"),
+            "{}",
+            err
+        );
+
+        let json = r#"{
+            "legend": { "G": "Grass" },
+            "tiles": [{ "name": "wide", "bitmap": ["GGG", "GGG"] }]
+        }"#;
+        let err = format!("{:#}", parse_tileset_json_str(json).unwrap_err());
+        assert!(err.contains("'wide'"), "{}", err);
+    }
+}
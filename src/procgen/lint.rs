@@ -0,0 +1,223 @@
+use std::{fmt, path::Path};
+
+use anyhow::Result;
+
+use crate::procgen::{
+    is_json_tileset, json_tileset::parse_tileset_json, parse::parse_tileset_xml_lenient,
+    types::Tileset,
+};
+
+const SIDE_NAMES: [&str; 4] = ["top", "left", "bottom", "right"];
+
+/// A problem in a tileset that can make generation fail or behave unexpectedly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintIssue {
+    /// a neighbor entry in the tileset file that couldn't be resolved, e.g. because it
+    /// references an unknown tile
+    InvalidNeighbor(String),
+
+    /// nothing may be placed on `side` of `variant`, so it can only appear along the border of
+    /// non-periodic output
+    NoNeighbor { variant: String, side: u8 },
+
+    /// `variant` can never be observed: every chain of neighbors it needs eventually runs into a
+    /// tile without a compatible neighbor, or (for tilesets built in code) its weight is 0
+    Unreachable { variant: String },
+
+    /// `to` is allowed on `side` of `from`, but `from` isn't allowed on the opposite side of `to`.
+    /// The loaders always allow both, so this only comes up for `allowed_neighbors` built or
+    /// edited in code
+    AsymmetricAdjacency { from: String, side: u8, to: String },
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidNeighbor(problem) => write!(f, "invalid {}", problem),
+            Self::NoNeighbor { variant, side } => write!(
+                f,
+                "{} has no compatible neighbor on its {} side",
+                variant, SIDE_NAMES[*side as usize]
+            ),
+            Self::Unreachable { variant } => write!(f, "{} can never appear", variant),
+            Self::AsymmetricAdjacency { from, side, to } => write!(
+                f,
+                "{} allows {} on its {} side, but not the other way around",
+                from, to, SIDE_NAMES[*side as usize]
+            ),
+        }
+    }
+}
+
+/// e.g. "road_turn (rotation 1, reflected)"
+fn describe_variant(tileset: &Tileset, variant: usize) -> String {
+    let tile = &tileset.variants[variant];
+    let name = &tileset.tile_names[tile.base_tile_idx];
+    match (tile.rotation, tile.reflected) {
+        (0, false) => name.clone(),
+        (rotation, false) => format!("{} (rotation {})", name, rotation),
+        (rotation, true) => format!("{} (rotation {}, reflected)", name, rotation),
+    }
+}
+
+/// Analyzes the adjacency rules of a tileset. An empty list means no problems were found
+pub fn lint_tileset(tileset: &Tileset) -> Vec<LintIssue> {
+    let num_variants = tileset.variants.len();
+    let allowed = &tileset.allowed_neighbors;
+    let mut issues = Vec::new();
+
+    for (variant, sides) in allowed.iter().enumerate() {
+        for (side, neighbors) in sides.iter().enumerate() {
+            if !neighbors.iter().any(|a| *a) {
+                issues.push(LintIssue::NoNeighbor {
+                    variant: describe_variant(tileset, variant),
+                    side: side as u8,
+                });
+            }
+
+            let opposite = (side + 2) % 4;
+            for (other, _) in neighbors.iter().enumerate().filter(|(_, a)| **a) {
+                if !allowed[other][opposite][variant] {
+                    issues.push(LintIssue::AsymmetricAdjacency {
+                        from: describe_variant(tileset, variant),
+                        side: side as u8,
+                        to: describe_variant(tileset, other),
+                    });
+                }
+            }
+        }
+    }
+
+    // Remove variants without a viable neighbor on some side until nothing changes. What's left
+    // is everything that can appear away from the border
+    let mut viable: Vec<bool> = (0..num_variants)
+        .map(|variant| tileset.weight(variant) > 0.0)
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for variant in 0..num_variants {
+            if !viable[variant] {
+                continue;
+            }
+            let supported = allowed[variant].iter().all(|side| {
+                side.iter()
+                    .zip(&viable)
+                    .any(|(allowed, viable)| *allowed && *viable)
+            });
+            if !supported {
+                viable[variant] = false;
+                changed = true;
+            }
+        }
+    }
+    for (variant, viable) in viable.iter().enumerate() {
+        if !viable {
+            issues.push(LintIssue::Unreachable {
+                variant: describe_variant(tileset, variant),
+            });
+        }
+    }
+
+    issues
+}
+
+/// Loads a tileset file (see [`crate::procgen::load_tileset`]) and lints it. For tileset XML this
+/// includes the neighbor entries the parser would reject
+pub fn lint_tileset_file<P: AsRef<Path>>(path: P, subset: Option<&str>) -> Result<Vec<LintIssue>> {
+    let path = path.as_ref();
+    if is_json_tileset(path) {
+        anyhow::ensure!(
+            subset.is_none(),
+            "subsets are only supported for tileset XML files"
+        );
+        return Ok(lint_tileset(&parse_tileset_json(path)?));
+    }
+
+    let (tileset, invalid_neighbors) = parse_tileset_xml_lenient(path, subset)?;
+    let mut issues: Vec<LintIssue> = invalid_neighbors
+        .into_iter()
+        .map(LintIssue::InvalidNeighbor)
+        .collect();
+    issues.extend(lint_tileset(&tileset));
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::tileset::make_island_race_tileset;
+
+    #[test]
+    fn island_tileset_is_clean() {
+        assert_eq!(lint_tileset(&make_island_race_tileset()), vec![]);
+    }
+
+    #[test]
+    fn flags_one_sided_and_missing_neighbors() {
+        let mut tileset = make_island_race_tileset();
+        let space = tileset
+            .variants
+            .iter()
+            .position(|v| tileset.tile_names[v.base_tile_idx] == "pure_space")
+            .unwrap();
+
+        // nothing may go above pure space anymore, but the others still allow it below them
+        tileset.allowed_neighbors[space][0].fill(false);
+        let issues = lint_tileset(&tileset);
+
+        assert!(issues.contains(&LintIssue::NoNeighbor {
+            variant: "pure_space".to_string(),
+            side: 0,
+        }));
+        assert!(issues.contains(&LintIssue::AsymmetricAdjacency {
+            from: "pure_space".to_string(),
+            side: 2,
+            to: "pure_space".to_string(),
+        }));
+        assert!(issues.contains(&LintIssue::Unreachable {
+            variant: "pure_space".to_string(),
+        }));
+    }
+
+    #[test]
+    fn reports_neighbors_the_xml_parser_would_reject() {
+        let dir = std::env::temp_dir().join(format!("wfc-lint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let knots = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/procgen/tilemaps/knots");
+        for image in ["empty.png", "line.png"] {
+            std::fs::copy(knots.join(image), dir.join(image)).unwrap();
+        }
+        std::fs::write(
+            dir.join("tileset.xml"),
+            r#"<set>
+                <tiles>
+                    <tile name="empty" symmetry="X"/>
+                    <tile name="line" symmetry="I"/>
+                </tiles>
+                <neighbors>
+                    <neighbor left="empty" right="empty"/>
+                    <neighbor left="line" right="line"/>
+                    <neighbor left="empty" right="line 1"/>
+                    <neighbor left="empty" right="road"/>
+                    <neighbor left="line 9" right="empty"/>
+                </neighbors>
+            </set>"#,
+        )
+        .unwrap();
+        let issues = lint_tileset_file(dir.join("tileset.xml"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let invalid: Vec<String> = issues
+            .unwrap()
+            .into_iter()
+            .filter_map(|issue| match issue {
+                LintIssue::InvalidNeighbor(problem) => Some(problem),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(invalid.len(), 2, "{:?}", invalid);
+        assert!(invalid[0].contains("unknown tile 'road'"), "{}", invalid[0]);
+        assert!(invalid[1].contains("line 9"), "{}", invalid[1]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::LazyLock;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Deserializer, Serialize, de};
+
+/// Surface type of a single bit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub name: String,
+    pub color: [f32; 4],
+
+    /// produces voxels. Non-solid materials are holes in the world
+    #[serde(default = "default_true")]
+    pub solid: bool,
+
+    /// part of an island, so the height map raises it and digs out dirt below it
+    #[serde(default = "default_true")]
+    pub island: bool,
+
+    /// always at the base height instead of getting random hills, like roads
+    #[serde(default)]
+    pub flat: bool,
+
+    /// counts as road for road constraints, road guides and the minimum road length
+    #[serde(default)]
+    pub road: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Index into a [`MaterialRegistry`]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize)]
+pub struct MaterialId(pub u16);
+
+impl MaterialId {
+    pub const ROAD: Self = Self(0);
+    pub const SPACE: Self = Self(1);
+    pub const GRASS: Self = Self(2);
+    pub const DIRT: Self = Self(3);
+    pub const EMPTY: Self = Self(4);
+}
+
+/// Worlds saved before the registry existed store bits by name ("Road", "Space", ...)
+impl<'de> Deserialize<'de> for MaterialId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Id(u16),
+            Name(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Id(id) => Ok(Self(id)),
+            Repr::Name(name) => BUILTIN
+                .find(&name)
+                .ok_or_else(|| de::Error::custom(format!("unknown material '{}'", name))),
+        }
+    }
+}
+
+static BUILTIN: LazyLock<MaterialRegistry> = LazyLock::new(|| MaterialRegistry {
+    materials: serde_json::from_str(include_str!("materials.json"))
+        .expect("built-in materials are valid"),
+});
+
+/// Every material a bitmap can use. The built-in materials come first, so their ids are the
+/// same in every registry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MaterialRegistry {
+    materials: Vec<Material>,
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        BUILTIN.clone()
+    }
+}
+
+impl MaterialRegistry {
+    pub fn get(&self, id: MaterialId) -> &Material {
+        &self.materials[id.0 as usize]
+    }
+
+    pub fn contains(&self, id: MaterialId) -> bool {
+        (id.0 as usize) < self.materials.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// Material with the given name, ignoring case
+    pub fn find(&self, name: &str) -> Option<MaterialId> {
+        self.materials
+            .iter()
+            .position(|material| material.name.eq_ignore_ascii_case(name))
+            .map(|idx| MaterialId(idx as u16))
+    }
+
+    /// Adds a material and returns its id. Errors if the name is already taken
+    pub fn add(&mut self, material: Material) -> Result<MaterialId> {
+        if self.find(&material.name).is_some() {
+            bail!("material '{}' is defined more than once", material.name);
+        }
+        if self.materials.len() > u16::MAX as usize {
+            bail!("too many materials");
+        }
+        self.materials.push(material);
+        Ok(MaterialId(self.materials.len() as u16 - 1))
+    }
+
+    /// Whether bits of material `id` count as road
+    pub fn is_road(&self, id: MaterialId) -> bool {
+        self.get(id).road
+    }
+
+    /// Material whose color is closest to `rgb`, ignoring `empty`
+    pub fn closest(&self, rgb: [u8; 3]) -> MaterialId {
+        let distance = |material: &Material| {
+            let [r, g, b, _] = material.color;
+            [r, g, b]
+                .iter()
+                .zip(rgb)
+                .map(|(c, p)| (c * 255.0 - p as f32).powi(2))
+                .sum::<f32>()
+        };
+
+        (0..self.materials.len() as u16)
+            .map(MaterialId)
+            .filter(|id| *id != MaterialId::EMPTY)
+            .min_by(|a, b| distance(self.get(*a)).total_cmp(&distance(self.get(*b))))
+            .expect("registry has materials besides empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_ids_match_names() {
+        let registry = MaterialRegistry::default();
+        for (id, name) in [
+            (MaterialId::ROAD, "road"),
+            (MaterialId::SPACE, "space"),
+            (MaterialId::GRASS, "grass"),
+            (MaterialId::DIRT, "dirt"),
+            (MaterialId::EMPTY, "empty"),
+        ] {
+            assert_eq!(registry.get(id).name, name);
+        }
+        assert!(registry.is_road(MaterialId::ROAD));
+        assert!(!registry.is_road(MaterialId::GRASS));
+    }
+
+    #[test]
+    fn reads_ids_and_legacy_names() {
+        let ids: Vec<MaterialId> = serde_json::from_str(r#"[0, "Space", "grass", 7]"#).unwrap();
+        assert_eq!(
+            ids,
+            [
+                MaterialId::ROAD,
+                MaterialId::SPACE,
+                MaterialId::GRASS,
+                MaterialId(7)
+            ]
+        );
+        assert!(serde_json::from_str::<MaterialId>(r#""lava""#).is_err());
+    }
+}
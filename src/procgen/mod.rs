@@ -1,26 +1,130 @@
+use std::path::Path;
+
+use anyhow::{Error, bail};
+
 use crate::{
-    procgen::{
-        types::Bit,
-        wfc::{Bitmap, HeightMap},
-    },
+    procgen::wfc::{Bitmap, HeightMap},
     scene::{Voxel, VoxelPos},
 };
 
+mod bitset;
+mod candidates;
+mod counts;
+mod generate;
+mod guide;
+mod json_tileset;
+mod lint;
+mod material;
+mod observer;
+mod overlapping;
 mod parse;
+mod roads;
+mod support;
 mod tileset;
 mod types;
 mod wfc;
 
-pub use tileset::make_island_race_tileset;
+pub use counts::{Amount, CountConstraints, TileCount};
+pub use generate::{GenerationReport, Generator};
+pub use guide::{Guide, GuideRule};
+pub use json_tileset::parse_tileset_json;
+pub use lint::{LintIssue, lint_tileset, lint_tileset_file};
+pub use material::{Material, MaterialId, MaterialRegistry};
+pub use observer::{GifRecorder, ProgressObserver, WfcObserver};
+pub use overlapping::{OverlappingConfig, OverlappingModel};
+pub use parse::parse_tileset_xml;
+pub use roads::RoadConstraint;
+pub use tileset::{island_race_pins, make_island_race_tileset};
+pub use types::Tileset;
 
-pub use wfc::WaveFunctionCollapse;
+pub use wfc::{
+    BacktrackConfig, Pin, Propagator, Region, SelectionHeuristic, SolverState,
+    WaveFunctionCollapse, WfcConfig,
+};
 
 use serde::{Deserialize, Serialize};
 
+/// Whether a tileset file is a bitmap tileset (JSON) rather than tileset XML
+fn is_json_tileset(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// Loads a bitmap tileset from a `.json` file, or a tileset XML file otherwise. Subsets only
+/// exist in tileset XML
+pub fn load_tileset<P: AsRef<Path>>(path: P, subset: Option<&str>) -> Result<Tileset, Error> {
+    let path = path.as_ref();
+    if !is_json_tileset(path) {
+        return parse_tileset_xml(path, subset);
+    }
+    if subset.is_some() {
+        bail!("subsets are only supported for tileset XML files");
+    }
+    parse_tileset_json(path)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct WorldDefinition {
     pub bitmap: Bitmap,
     pub height_map: HeightMap,
+
+    /// generated with wrapping edges, so regenerating a region has to wrap around as well
+    #[serde(default)]
+    pub periodic: bool,
+}
+
+impl WorldDefinition {
+    /// Errors if the bitmap and height map don't describe the same width x height grid, or the
+    /// bitmap uses materials it doesn't define
+    pub fn validate(&self) -> Result<(), Error> {
+        let (width, height) = (self.bitmap.width, self.bitmap.height);
+        if self.bitmap.bits.len() != width * height {
+            bail!(
+                "bitmap is {}x{} but has {} bits",
+                width,
+                height,
+                self.bitmap.bits.len()
+            );
+        }
+
+        let hm = &self.height_map;
+        // older worlds don't record the height map dimensions
+        let recorded = (hm.width, hm.height) != (0, 0);
+        if recorded && (hm.width, hm.height) != (width, height) {
+            bail!(
+                "height map is {}x{} but bitmap is {}x{}",
+                hm.width,
+                hm.height,
+                width,
+                height
+            );
+        }
+        if hm.bottoms.len() != width * height || hm.tops.len() != width * height {
+            bail!(
+                "height map has {} bottoms and {} tops, expected {} for a {}x{} bitmap",
+                hm.bottoms.len(),
+                hm.tops.len(),
+                width * height,
+                width,
+                height
+            );
+        }
+
+        let materials = &self.bitmap.materials;
+        if let Some(bit) = self
+            .bitmap
+            .bits
+            .iter()
+            .find(|bit| !materials.contains(**bit))
+        {
+            bail!(
+                "bitmap uses material {} but only {} are defined",
+                bit.0,
+                materials.len()
+            );
+        }
+        Ok(())
+    }
 }
 
 pub fn bitmap_to_voxels(world_def: WorldDefinition) -> Vec<Voxel> {
@@ -28,23 +132,25 @@ pub fn bitmap_to_voxels(world_def: WorldDefinition) -> Vec<Voxel> {
 
     let bitmap = world_def.bitmap;
     let height_map = world_def.height_map;
+    let materials = &bitmap.materials;
+    let dirt_color = materials.get(MaterialId::DIRT).color;
 
     for x in 0..bitmap.width {
         for y in 0..bitmap.height {
-            let bit = bitmap.bits[y * bitmap.width + x];
-            if !matches!(bit, Bit::Space) {
+            let material = materials.get(bitmap.bits[y * bitmap.width + x]);
+            if material.solid {
                 let bottom = height_map.bottoms[y * bitmap.width + x];
                 let top = height_map.tops[y * bitmap.width + x];
 
                 for level in bottom..0 {
                     let pos = VoxelPos::new(x.try_into().unwrap(), level, y.try_into().unwrap());
-                    let voxel = Voxel::new(pos, 1.0, 1.0, 1.0, Bit::Dirt.color());
+                    let voxel = Voxel::new(pos, 1.0, 1.0, 1.0, dirt_color);
                     voxels.push(voxel);
                 }
 
                 for level in 0..top {
                     let pos = VoxelPos::new(x.try_into().unwrap(), level, y.try_into().unwrap());
-                    let voxel = Voxel::new(pos, 1.0, 1.0, 1.0, bit.color());
+                    let voxel = Voxel::new(pos, 1.0, 1.0, 1.0, material.color);
                     voxels.push(voxel);
                 }
             }
@@ -53,3 +159,98 @@ pub fn bitmap_to_voxels(world_def: WorldDefinition) -> Vec<Voxel> {
 
     voxels
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 5 wide, 3 tall: a road along the middle row, grass above it and space below
+    fn non_square_bitmap() -> Bitmap {
+        let mut bits = vec![MaterialId::GRASS; 5];
+        bits.extend([MaterialId::ROAD; 5]);
+        bits.extend([MaterialId::SPACE; 5]);
+        Bitmap {
+            bits,
+            width: 5,
+            height: 3,
+            materials: MaterialRegistry::default(),
+        }
+    }
+
+    #[test]
+    fn height_map_matches_non_square_bitmap() {
+        let bitmap = non_square_bitmap();
+        let height_map = bitmap.compute_height_map(0);
+
+        assert_eq!((height_map.width, height_map.height), (5, 3));
+        assert_eq!(height_map.bottoms.len(), 15);
+        assert_eq!(height_map.tops.len(), 15);
+        for x in 0..5 {
+            // road is always flat, space is never raised or lowered
+            assert_eq!(height_map.tops[5 + x], 1);
+            assert_eq!(height_map.bottoms[10 + x], 0);
+            assert_eq!(height_map.tops[10 + x], 1);
+        }
+    }
+
+    #[test]
+    fn voxels_cover_non_square_bitmap() {
+        let bitmap = non_square_bitmap();
+        let height_map = HeightMap {
+            bottoms: vec![-1; 15],
+            tops: vec![2; 15],
+            width: 5,
+            height: 3,
+        };
+        let world_def = WorldDefinition {
+            bitmap,
+            height_map,
+            periodic: false,
+        };
+        world_def.validate().unwrap();
+
+        let voxels = bitmap_to_voxels(world_def);
+
+        // 10 non-space cells, each with 1 dirt voxel below and 2 above
+        assert_eq!(voxels.len(), 30);
+        for voxel in &voxels {
+            assert!((0..5).contains(&voxel.pos.x));
+            assert!((0..2).contains(&voxel.pos.z), "space row has no voxels");
+            assert!((-1..2).contains(&voxel.pos.y));
+        }
+        assert!(voxels.iter().any(|v| v.pos == VoxelPos::new(4, 1, 1)));
+    }
+
+    #[test]
+    fn mismatched_dimensions_are_rejected() {
+        let bitmap = non_square_bitmap();
+        let height_map = HeightMap {
+            bottoms: vec![0; 15],
+            tops: vec![1; 15],
+            width: 3,
+            height: 5,
+        };
+        let world_def = WorldDefinition {
+            bitmap,
+            height_map,
+            periodic: false,
+        };
+        assert!(world_def.validate().is_err());
+    }
+
+    #[test]
+    fn older_worlds_are_not_periodic() {
+        let bitmap = non_square_bitmap();
+        let height_map = bitmap.compute_height_map(0);
+        let mut json = serde_json::to_value(WorldDefinition {
+            bitmap,
+            height_map,
+            periodic: true,
+        })
+        .unwrap();
+        json.as_object_mut().unwrap().remove("periodic");
+
+        let world_def: WorldDefinition = serde_json::from_value(json).unwrap();
+        assert!(!world_def.periodic);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use anyhow::{Context, Error};
+use image::{
+    Frame,
+    codecs::gif::{GifEncoder, Repeat},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::procgen::wfc::{Contradiction, WaveFunctionCollapse};
+
+/// Hooks into a running [`WaveFunctionCollapse`], e.g. to show progress or record frames.
+/// Every callback does nothing by default, so observers only implement what they need. Setting
+/// up the wave (constraints and pins) isn't reported, only the steps after it
+pub trait WfcObserver {
+    /// `option` was picked for tile (x, y) and propagated without a contradiction. Backtracking
+    /// can still undo it later
+    fn cell_observed(&mut self, _wfc: &WaveFunctionCollapse, _x: usize, _y: usize, _option: usize) {
+    }
+
+    /// `option` was removed from unobserved tile (x, y), by propagation, a global constraint or
+    /// backtracking banning it. Called in the middle of propagation, so the wave isn't passed
+    /// along. Options that backtracking brings back aren't reported
+    fn option_removed(&mut self, _x: usize, _y: usize, _option: usize) {}
+
+    /// The run stopped at a contradiction it couldn't backtrack out of
+    fn contradiction(&mut self, _wfc: &WaveFunctionCollapse, _contradiction: Contradiction) {}
+
+    /// Every tile has been observed
+    fn finished(&mut self, _wfc: &WaveFunctionCollapse) {}
+}
+
+/// Observes nothing
+impl WfcObserver for () {}
+
+/// Passes every event to both observers
+impl<A: WfcObserver, B: WfcObserver> WfcObserver for (A, B) {
+    fn cell_observed(&mut self, wfc: &WaveFunctionCollapse, x: usize, y: usize, option: usize) {
+        self.0.cell_observed(wfc, x, y, option);
+        self.1.cell_observed(wfc, x, y, option);
+    }
+
+    fn option_removed(&mut self, x: usize, y: usize, option: usize) {
+        self.0.option_removed(x, y, option);
+        self.1.option_removed(x, y, option);
+    }
+
+    fn contradiction(&mut self, wfc: &WaveFunctionCollapse, contradiction: Contradiction) {
+        self.0.contradiction(wfc, contradiction);
+        self.1.contradiction(wfc, contradiction);
+    }
+
+    fn finished(&mut self, wfc: &WaveFunctionCollapse) {
+        self.0.finished(wfc);
+        self.1.finished(wfc);
+    }
+}
+
+/// An observer that might not be there
+impl<T: WfcObserver> WfcObserver for Option<T> {
+    fn cell_observed(&mut self, wfc: &WaveFunctionCollapse, x: usize, y: usize, option: usize) {
+        if let Some(observer) = self {
+            observer.cell_observed(wfc, x, y, option);
+        }
+    }
+
+    fn option_removed(&mut self, x: usize, y: usize, option: usize) {
+        if let Some(observer) = self {
+            observer.option_removed(x, y, option);
+        }
+    }
+
+    fn contradiction(&mut self, wfc: &WaveFunctionCollapse, contradiction: Contradiction) {
+        if let Some(observer) = self {
+            observer.contradiction(wfc, contradiction);
+        }
+    }
+
+    fn finished(&mut self, wfc: &WaveFunctionCollapse) {
+        if let Some(observer) = self {
+            observer.finished(wfc);
+        }
+    }
+}
+
+/// Progress bar of observed tiles. A new bar starts with each run, so restarts after a
+/// contradiction show up as bars of their own
+#[derive(Default)]
+pub struct ProgressObserver {
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressObserver {
+    fn bar(&mut self, wfc: &WaveFunctionCollapse) -> &ProgressBar {
+        self.bar.get_or_insert_with(|| {
+            let bar = ProgressBar::new(wfc.wave.tiles.len() as u64);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            bar
+        })
+    }
+}
+
+impl WfcObserver for ProgressObserver {
+    fn cell_observed(&mut self, wfc: &WaveFunctionCollapse, _x: usize, _y: usize, _option: usize) {
+        let observed = wfc.wave.tiles.len() - wfc.unobserved();
+        self.bar(wfc).set_position(observed as u64);
+    }
+
+    fn contradiction(&mut self, wfc: &WaveFunctionCollapse, _contradiction: Contradiction) {
+        self.bar(wfc).finish_with_message("contradiction!");
+        self.bar = None;
+    }
+
+    fn finished(&mut self, wfc: &WaveFunctionCollapse) {
+        let bar = self.bar(wfc);
+        bar.set_position(wfc.wave.tiles.len() as u64);
+        bar.finish_with_message("done");
+        self.bar = None;
+    }
+}
+
+/// Writes a frame of the wave after every observation to a GIF. Frames are encoded as they come
+/// instead of being kept around. A contradiction starts the file over, so after restarts it
+/// only shows the run that finished
+pub struct GifRecorder {
+    path: PathBuf,
+    encoder: Option<GifEncoder<BufWriter<File>>>,
+
+    /// first error while writing, reported by [`Self::finish`]
+    error: Option<Error>,
+}
+
+impl GifRecorder {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            encoder: None,
+            error: None,
+        }
+    }
+
+    fn write_frame(&mut self, wfc: &WaveFunctionCollapse) -> Result<(), Error> {
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            None => {
+                let file = File::create(&self.path)
+                    .with_context(|| format!("Failed to create gif: {}", self.path.display()))?;
+                let mut encoder = GifEncoder::new(BufWriter::new(file));
+                encoder.set_repeat(Repeat::Infinite)?;
+                self.encoder.insert(encoder)
+            }
+        };
+        encoder.encode_frame(Frame::new(wfc.frame().to_rgba8()))?;
+        Ok(())
+    }
+
+    /// Finishes the GIF. Errors if writing any frame failed
+    pub fn finish(mut self) -> Result<(), Error> {
+        // dropping the encoder writes the end of the file
+        self.encoder = None;
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl WfcObserver for GifRecorder {
+    fn cell_observed(&mut self, wfc: &WaveFunctionCollapse, _x: usize, _y: usize, _option: usize) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_frame(wfc) {
+            self.error = Some(e);
+        }
+    }
+
+    fn contradiction(&mut self, _wfc: &WaveFunctionCollapse, _contradiction: Contradiction) {
+        // the next run creates the file again
+        self.encoder = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::{WfcConfig, tileset::make_island_race_tileset, wfc::WaveTile};
+
+    /// Records every event
+    #[derive(Default)]
+    struct Recorder {
+        observed: Vec<(usize, usize, usize)>,
+        removed: Vec<(usize, usize, usize)>,
+        contradictions: usize,
+        finished: usize,
+    }
+
+    impl WfcObserver for Recorder {
+        fn cell_observed(
+            &mut self,
+            _wfc: &WaveFunctionCollapse,
+            x: usize,
+            y: usize,
+            option: usize,
+        ) {
+            self.observed.push((x, y, option));
+        }
+
+        fn option_removed(&mut self, x: usize, y: usize, option: usize) {
+            self.removed.push((x, y, option));
+        }
+
+        fn contradiction(&mut self, _wfc: &WaveFunctionCollapse, _contradiction: Contradiction) {
+            self.contradictions += 1;
+        }
+
+        fn finished(&mut self, _wfc: &WaveFunctionCollapse) {
+            self.finished += 1;
+        }
+    }
+
+    #[test]
+    fn observer_sees_every_step() {
+        let mut wfc =
+            WaveFunctionCollapse::new(make_island_race_tileset(), 6, 6, 0, WfcConfig::default())
+                .unwrap();
+        let mut recorder = Recorder::default();
+        wfc.step_all(&mut recorder).unwrap();
+
+        assert_eq!(recorder.observed.len(), wfc.steps());
+        assert_eq!((recorder.contradictions, recorder.finished), (0, 1));
+        for &(x, y, option) in &recorder.observed {
+            assert!(matches!(wfc.wave.get(x, y), WaveTile::Observed(o) if *o == option));
+        }
+
+        // whatever was removed from a tile is not what it ended up as
+        assert!(!recorder.removed.is_empty());
+        for &(x, y, option) in &recorder.removed {
+            assert!(!matches!(wfc.wave.get(x, y), WaveTile::Observed(o) if *o == option));
+        }
+    }
+}
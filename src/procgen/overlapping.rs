@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use image::{DynamicImage, ImageBuffer, Rgb};
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+
+use crate::procgen::{
+    bitset::BitSet,
+    candidates::{Candidates, entropy_priority},
+    generate::{AttemptFailure, GenerationReport, run_attempts},
+    material::MaterialRegistry,
+    support::SupportCounts,
+    wfc::{Bitmap, Contradiction, Wave, WaveTile},
+};
+
+/// How patterns are learned from the sample image and how the output is laid out
+#[derive(Debug, Clone)]
+pub struct OverlappingConfig {
+    /// width and height of the extracted patterns, in pixels
+    pub pattern_size: usize,
+
+    /// also learn the 3 rotations of every pattern
+    pub rotations: bool,
+
+    /// also learn the mirror image of every pattern (and of its rotations)
+    pub reflections: bool,
+
+    /// patterns wrap around the edges of the sample
+    pub periodic_input: bool,
+
+    /// wrap around the edges of the output so it tiles seamlessly
+    pub periodic: bool,
+}
+
+impl Default for OverlappingConfig {
+    fn default() -> Self {
+        Self {
+            pattern_size: 3,
+            rotations: true,
+            reflections: true,
+            periodic_input: false,
+            periodic: false,
+        }
+    }
+}
+
+/// Grid of RGB colors produced by the overlapping model
+#[derive(Debug, Clone)]
+pub struct ColorGrid {
+    pub colors: Vec<[u8; 3]>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl ColorGrid {
+    pub fn render_to_image(&self) -> DynamicImage {
+        let mut img: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::new(self.width as u32, self.height as u32);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                img.put_pixel(x as u32, y as u32, Rgb(self.colors[y * self.width + x]));
+            }
+        }
+
+        DynamicImage::from(img)
+    }
+
+    /// Maps every color to the built-in material with the closest color, so the output can be
+    /// turned into a world
+    pub fn to_bitmap(&self) -> Bitmap {
+        let materials = MaterialRegistry::default();
+        let bits = self
+            .colors
+            .iter()
+            .map(|&color| materials.closest(color))
+            .collect();
+
+        Bitmap {
+            bits,
+            width: self.width,
+            height: self.height,
+            materials,
+        }
+    }
+}
+
+/// NxN block of palette indices in row-major order
+type Pattern = Vec<u8>;
+
+/// Overlapping-model WFC: every wave tile is the top left corner of an NxN pattern seen in the
+/// sample, and neighboring patterns have to agree wherever they overlap
+pub struct OverlappingModel {
+    palette: Vec<[u8; 3]>,
+    pattern_size: usize,
+    patterns: Vec<Pattern>,
+
+    /// number of times each pattern was seen in the sample
+    weights: Vec<f64>,
+
+    /// `masks[pattern][side]` holds every pattern allowed on `side` of `pattern`
+    masks: Vec<[BitSet; 4]>,
+    periodic: bool,
+}
+
+impl OverlappingModel {
+    pub fn from_image(sample: &DynamicImage, config: &OverlappingConfig) -> Result<Self, Error> {
+        let n = config.pattern_size;
+        let sample = sample.to_rgb8();
+        let (sample_width, sample_height) = (sample.width() as usize, sample.height() as usize);
+        if n == 0 || n > sample_width || n > sample_height {
+            return Err(anyhow::anyhow!(
+                "pattern size {} doesn't fit the {}x{} sample",
+                n,
+                sample_width,
+                sample_height
+            ));
+        }
+
+        let mut palette: Vec<[u8; 3]> = Vec::new();
+        let mut indices = Vec::with_capacity(sample_width * sample_height);
+        for pixel in sample.pixels() {
+            let color = pixel.0;
+            let idx = match palette.iter().position(|c| *c == color) {
+                Some(idx) => idx,
+                None => {
+                    if palette.len() > u8::MAX as usize {
+                        return Err(anyhow::anyhow!("sample has more than 256 colors"));
+                    }
+                    palette.push(color);
+                    palette.len() - 1
+                }
+            };
+            indices.push(idx as u8);
+        }
+
+        let (max_x, max_y) = if config.periodic_input {
+            (sample_width, sample_height)
+        } else {
+            (sample_width - n + 1, sample_height - n + 1)
+        };
+
+        // patterns in order of first appearance, so the same sample always gives the same model
+        let mut patterns: Vec<Pattern> = Vec::new();
+        let mut weights: Vec<f64> = Vec::new();
+        let mut pattern_ids: HashMap<Pattern, usize> = HashMap::new();
+        for y in 0..max_y {
+            for x in 0..max_x {
+                let pattern: Pattern = (0..n * n)
+                    .map(|i| {
+                        let px = (x + i % n) % sample_width;
+                        let py = (y + i / n) % sample_height;
+                        indices[py * sample_width + px]
+                    })
+                    .collect();
+
+                for variant in variants(pattern, n, config) {
+                    let id = *pattern_ids.entry(variant.clone()).or_insert_with(|| {
+                        patterns.push(variant);
+                        weights.push(0.0);
+                        patterns.len() - 1
+                    });
+                    weights[id] += 1.0;
+                }
+            }
+        }
+
+        let num_patterns = patterns.len();
+        let masks = patterns
+            .iter()
+            .map(|pattern| {
+                [(0, -1), (-1, 0), (0, 1), (1, 0)].map(|(dx, dy)| {
+                    let mut mask = BitSet::empty(num_patterns);
+                    for (other_id, other) in patterns.iter().enumerate() {
+                        if agrees(pattern, other, n, dx, dy) {
+                            mask.insert(other_id);
+                        }
+                    }
+                    mask
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            palette,
+            pattern_size: n,
+            patterns,
+            weights,
+            masks,
+            periodic: config.periodic,
+        })
+    }
+
+    /// number of distinct patterns learned from the sample
+    pub fn num_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Runs until an attempt finishes without a contradiction, restarting with a derived seed
+    /// after each failure. Errors if all attempts fail
+    pub fn generate(
+        &self,
+        width: usize,
+        height: usize,
+        seed: u64,
+        max_attempts: usize,
+    ) -> Result<(ColorGrid, GenerationReport), Error> {
+        if width == 0 || height == 0 {
+            return Err(anyhow::anyhow!("{}x{} output is empty", width, height));
+        }
+        if !self.periodic && (width < self.pattern_size || height < self.pattern_size) {
+            return Err(anyhow::anyhow!(
+                "{}x{} output is smaller than the {}x{} patterns",
+                width,
+                height,
+                self.pattern_size,
+                self.pattern_size
+            ));
+        }
+
+        run_attempts(seed, max_attempts, |attempt_seed| {
+            Ok(self.run(width, height, attempt_seed))
+        })
+    }
+
+    /// A single attempt at collapsing a `width` x `height` output
+    fn run(&self, width: usize, height: usize, seed: u64) -> Result<ColorGrid, AttemptFailure> {
+        let n = self.pattern_size;
+
+        // without wrapping, the last pattern in a row/column also covers the n - 1 pixels after it
+        let (wave_width, wave_height) = if self.periodic {
+            (width, height)
+        } else {
+            (width - n + 1, height - n + 1)
+        };
+        let num_tiles = wave_width * wave_height;
+
+        let mut wave = Wave {
+            tiles: vec![WaveTile::Unobserved(BitSet::full(self.patterns.len())); num_tiles],
+            width: wave_width,
+            height: wave_height,
+            periodic: self.periodic,
+        };
+        let mut supports = SupportCounts::new(&self.masks, &wave);
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let tiebreaks: Vec<u32> = (0..num_tiles).map(|_| rng.random()).collect();
+
+        let mut candidates = Candidates::new(num_tiles);
+        let push_candidates = |candidates: &mut Candidates, wave: &Wave, tiles: &[usize]| {
+            for &idx in tiles {
+                if let WaveTile::Unobserved(options) = &wave.tiles[idx] {
+                    let weights = options.iter().map(|pattern| self.weights[pattern]);
+                    candidates.push(
+                        idx,
+                        entropy_priority(weights, tiebreaks[idx]),
+                        tiebreaks[idx],
+                    );
+                }
+            }
+        };
+        let mut touched = Vec::new();
+
+        let mut step = 0;
+        let failure = |step, contradiction| AttemptFailure {
+            seed,
+            step,
+            contradiction,
+        };
+
+        let removals = supports.unsupported(&wave);
+        supports
+            .propagate(&self.masks, &mut wave, removals, |_, _| {})
+            .map_err(|c| failure(step, c))?;
+        let all: Vec<usize> = (0..num_tiles).collect();
+        push_candidates(&mut candidates, &wave, &all);
+
+        while let Some(idx) = candidates.pop(&wave) {
+            step += 1;
+
+            let WaveTile::Unobserved(options) = &wave.tiles[idx] else {
+                unreachable!("only unobserved tiles are picked");
+            };
+            let possible: Vec<usize> = options.iter().collect();
+            let observation = *possible
+                .choose_weighted(&mut rng, |&pattern| self.weights[pattern])
+                .map_err(|_| {
+                    let (x, y) = (idx % wave_width, idx / wave_width);
+                    failure(
+                        step,
+                        Contradiction {
+                            x,
+                            y,
+                            neighbor: None,
+                        },
+                    )
+                })?;
+
+            let mut removed = options.clone();
+            removed.remove(observation);
+            wave.tiles[idx] = WaveTile::Observed(observation);
+            supports
+                .propagate_observed(&self.masks, &mut wave, idx, &removed, |idx, _| {
+                    touched.push(idx)
+                })
+                .map_err(|c| failure(step, c))?;
+            touched.sort_unstable();
+            touched.dedup();
+            push_candidates(&mut candidates, &wave, &touched);
+            touched.clear();
+        }
+
+        let mut colors = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (tile_x, tile_y) = (x.min(wave_width - 1), y.min(wave_height - 1));
+                let WaveTile::Observed(pattern) = wave.get(tile_x, tile_y) else {
+                    unreachable!("every tile is observed once no candidates are left");
+                };
+                let (dx, dy) = (x - tile_x, y - tile_y);
+                let color_idx = self.patterns[*pattern][dy * n + dx];
+                colors.push(self.palette[color_idx as usize]);
+            }
+        }
+
+        Ok(ColorGrid {
+            colors,
+            width,
+            height,
+        })
+    }
+}
+
+/// `pattern` rotated 90 degrees clockwise
+fn rotate(pattern: &Pattern, n: usize) -> Pattern {
+    (0..n * n)
+        .map(|i| {
+            let (x, y) = (i % n, i / n);
+            pattern[(n - 1 - x) * n + y]
+        })
+        .collect()
+}
+
+/// `pattern` mirrored left to right
+fn reflect(pattern: &Pattern, n: usize) -> Pattern {
+    (0..n * n)
+        .map(|i| {
+            let (x, y) = (i % n, i / n);
+            pattern[y * n + (n - 1 - x)]
+        })
+        .collect()
+}
+
+/// The pattern itself plus the rotations/reflections enabled in the config. Symmetric patterns
+/// show up more than once, which counts towards their weight like in the sample
+fn variants(pattern: Pattern, n: usize, config: &OverlappingConfig) -> Vec<Pattern> {
+    let mut variants = vec![pattern];
+    if config.rotations {
+        for _ in 0..3 {
+            let rotated = rotate(variants.last().unwrap(), n);
+            variants.push(rotated);
+        }
+    }
+    if config.reflections {
+        let reflected: Vec<Pattern> = variants.iter().map(|p| reflect(p, n)).collect();
+        variants.extend(reflected);
+    }
+    variants
+}
+
+/// whether `b` placed at offset (dx, dy) from `a` has the same colors wherever they overlap
+fn agrees(a: &Pattern, b: &Pattern, n: usize, dx: isize, dy: isize) -> bool {
+    let n = n as isize;
+    let (x_min, x_max) = (dx.max(0), (n + dx).min(n));
+    let (y_min, y_max) = (dy.max(0), (n + dy).min(n));
+    (y_min..y_max).all(|y| {
+        (x_min..x_max).all(|x| a[(y * n + x) as usize] == b[((y - dy) * n + (x - dx)) as usize])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `size` x `size` sample of horizontal stripes alternating between two colors
+    fn stripes(size: u32) -> DynamicImage {
+        let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(size, size, |_, y| {
+            if y % 2 == 0 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        DynamicImage::from(img)
+    }
+
+    #[test]
+    fn learns_patterns_and_variants() {
+        let config = OverlappingConfig {
+            pattern_size: 2,
+            rotations: false,
+            reflections: false,
+            ..Default::default()
+        };
+        let model = OverlappingModel::from_image(&stripes(4), &config).unwrap();
+        assert_eq!(model.num_patterns(), 2);
+
+        let config = OverlappingConfig {
+            pattern_size: 2,
+            ..Default::default()
+        };
+        let model = OverlappingModel::from_image(&stripes(4), &config).unwrap();
+        // horizontal and vertical stripes, each starting with either color
+        assert_eq!(model.num_patterns(), 4);
+    }
+
+    #[test]
+    fn output_only_contains_sample_patterns() {
+        let config = OverlappingConfig {
+            pattern_size: 2,
+            rotations: false,
+            reflections: false,
+            ..Default::default()
+        };
+        let model = OverlappingModel::from_image(&stripes(4), &config).unwrap();
+        let (grid, _) = model.generate(7, 5, 3, 1).unwrap();
+
+        assert_eq!((grid.width, grid.height), (7, 5));
+        for y in 0..grid.height {
+            let row = &grid.colors[y * grid.width..(y + 1) * grid.width];
+            assert!(row.iter().all(|c| *c == row[0]), "rows are a single color");
+            if y > 0 {
+                assert_ne!(
+                    row[0],
+                    grid.colors[(y - 1) * grid.width],
+                    "colors alternate"
+                );
+            }
+        }
+    }
+}
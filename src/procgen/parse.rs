@@ -1,183 +1,534 @@
-// use crate::procgen::types::{BaseTile, Neighbor, Symmetry, Tileset};
-// use anyhow::{Context, Result};
-// use serde::Deserialize;
-// use std::collections::{HashMap, HashSet};
-// use std::fs;
-// use std::path::Path;
-
-// #[derive(Debug, Deserialize)]
-// struct TilesetDefinition {
-//     tiles: TilesDef,
-//     neighbors: NeighborsDef,
-// }
-
-// #[derive(Debug, Deserialize)]
-// struct TilesDef {
-//     #[serde(rename = "tile")]
-//     tiles: Vec<TileDef>,
-// }
-
-// #[derive(Debug, Deserialize)]
-// struct TileDef {
-//     #[serde(rename = "@name")]
-//     name: String,
-//     #[serde(rename = "@symmetry")]
-//     symmetry: String,
-//     #[serde(rename = "@weight", default = "default_weight")]
-//     weight: f32,
-// }
-
-// fn default_weight() -> f32 {
-//     1.0
-// }
-
-// #[derive(Debug, Deserialize)]
-// struct NeighborsDef {
-//     #[serde(rename = "neighbor")]
-//     neighbors: Vec<NeighborDef>,
-// }
-
-// #[derive(Debug, Deserialize)]
-// struct NeighborDef {
-//     #[serde(rename = "@left")]
-//     left: String,
-//     #[serde(rename = "@right")]
-//     right: String,
-// }
-
-// /// Parses a tile reference string like "corner 1" into (tile_name, rotation)
-// /// where rotation is 0, 1, 2, or 3 (representing R0, R1, R2, R3)
-// fn parse_tile_ref(s: &str) -> (&str, u8) {
-//     let parts: Vec<&str> = s.trim().split_whitespace().collect();
-//     if parts.len() == 1 {
-//         (parts[0], 0)
-//     } else if parts.len() == 2 {
-//         let rotation = parts[1].parse::<u8>().unwrap_or(0);
-//         (parts[0], rotation)
-//     } else {
-//         (s, 0)
-//     }
-// }
-
-// /// Parse a tileset from an XML file
-// pub fn parse_tileset_xml<P: AsRef<Path>>(xml_path: P) -> Result<Tileset> {
-//     let xml_path = xml_path.as_ref();
-//     let xml_content = fs::read_to_string(xml_path)
-//         .with_context(|| format!("Failed to read XML file: {}", xml_path.display()))?;
-
-//     let def: TilesetDefinition =
-//         quick_xml::de::from_str(&xml_content).context("Failed to deserialize XML")?;
-
-//     // PNGs are in the same directory as the XML file
-//     let base_dir = xml_path
-//         .parent()
-//         .unwrap_or_else(|| Path::new("."))
-//         .to_path_buf();
-
-//     // Load tile images
-//     let mut tiles = HashMap::new();
-//     let mut tile_names = Vec::new();
-//     let mut tile_weights = Vec::new();
-//     let mut name_to_index = HashMap::new();
-//     for (idx, tile_def) in def.tiles.tiles.iter().enumerate() {
-//         let tile_path = base_dir.join(format!("{}.png", tile_def.name));
-//         let img = image::open(&tile_path)
-//             .with_context(|| format!("Failed to load tile image: {}", tile_path.display()))?;
-
-//         let symmetry = Symmetry::from_str(&tile_def.symmetry)?;
-//         tiles.insert(tile_def.name.clone(), BaseTile { img, symmetry });
-//         tile_names.push(tile_def.name.clone());
-//         tile_weights.push(tile_def.weight);
-//         name_to_index.insert(tile_def.name.as_str(), idx);
-//     }
-
-//     // Parse neighbors
-//     let mut neighbors = HashSet::new();
-//     for neighbor_def in &def.neighbors.neighbors {
-//         let (name_a, rot_a) = parse_tile_ref(&neighbor_def.left);
-//         let (name_b, rot_b) = parse_tile_ref(&neighbor_def.right);
-
-//         let idx_a = *name_to_index
-//             .get(name_a)
-//             .expect("neighbor references unknown tile");
-//         let idx_b = *name_to_index
-//             .get(name_b)
-//             .expect("neighbor references unknown tile");
-
-//         let symmetry_a = tiles
-//             .get(name_a)
-//             .expect("neighbor references unknown tile")
-//             .symmetry;
-//         let symmetry_b = tiles
-//             .get(name_b)
-//             .expect("neighbor references unknown tile")
-//             .symmetry;
-
-//         let sides_a = symmetry_a.symmetric_sides((3 + (4 - rot_a)) % 4);
-//         let sides_b = symmetry_b.symmetric_sides((1 + (4 - rot_b)) % 4);
-
-//         // Expand into individual side-to-side neighbors
-//         for &side_a in &sides_a {
-//             for &side_b in &sides_b {
-//                 neighbors.insert(Neighbor {
-//                     tile_one_idx: idx_a,
-//                     side_one: side_a,
-//                     tile_two_idx: idx_b,
-//                     side_two: side_b,
-//                 });
-//             }
-//         }
-//     }
-
-//     // let tile_size = tiles.get(tile_names.first().unwrap()).unwrap().img.width() as usize;
-
-//     // Pre-compute allowed neighbors
-//     let num_tiles = tile_names.len();
-//     let num_rotated_tiles = num_tiles * 4;
-//     let mut allowed_neighbors = Vec::with_capacity(num_rotated_tiles);
-
-//     for _ in 0..num_rotated_tiles {
-//         allowed_neighbors.push([
-//             vec![false; num_rotated_tiles],
-//             vec![false; num_rotated_tiles],
-//             vec![false; num_rotated_tiles],
-//             vec![false; num_rotated_tiles],
-//         ]);
-//     }
-
-//     // For each neighbor relationship, mark all compatible rotations
-//     for neighbor in &neighbors {
-//         // Try all rotation combinations
-//         for rot_one in 0..4u8 {
-//             for rot_two in 0..4u8 {
-//                 let tile_one_idx = neighbor.tile_one_idx * 4 + rot_one as usize;
-//                 let tile_two_idx = neighbor.tile_two_idx * 4 + rot_two as usize;
-
-//                 for side in 0..4u8 {
-//                     let side_one = (side + (4 - rot_one)) % 4;
-//                     let side_two = (side + 2 + (4 - rot_two)) % 4;
-
-//                     if neighbor.side_one == side_one && neighbor.side_two == side_two {
-//                         allowed_neighbors[tile_one_idx][side as usize][tile_two_idx] = true;
-//                     }
-
-//                     // Also check the other way
-//                     let side_one_rev = (side + (4 - rot_two)) % 4;
-//                     let side_two_rev = (side + 2 + (4 - rot_one)) % 4;
-
-//                     if neighbor.side_two == side_one_rev && neighbor.side_one == side_two_rev {
-//                         allowed_neighbors[tile_two_idx][side as usize][tile_one_idx] = true;
-//                     }
-//                 }
-//             }
-//         }
-//     }
-
-//     Ok(Tileset {
-//         tiles,
-//         tile_names,
-//         tile_size,
-//         allowed_neighbors,
-//         tile_weights,
-//     })
-// }
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use image::{DynamicImage, imageops::FilterType};
+use serde::Deserialize;
+
+use crate::procgen::{
+    material::MaterialRegistry,
+    types::{BaseTile, Symmetry, TileBitmap, Tileset, tile_variants},
+};
+
+#[derive(Debug, Deserialize)]
+struct TilesetDefinition {
+    /// "True" if every orientation of a tile has its own image (`name 0.png`, `name 1.png`, ...)
+    #[serde(rename = "@unique", default)]
+    unique: Option<String>,
+    tiles: TilesDef,
+    neighbors: NeighborsDef,
+    subsets: Option<SubsetsDef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TilesDef {
+    #[serde(rename = "tile")]
+    tiles: Vec<TileDef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TileDef {
+    #[serde(rename = "@name")]
+    name: String,
+    #[serde(rename = "@symmetry", default = "default_symmetry")]
+    symmetry: String,
+    #[serde(rename = "@weight", default = "default_weight")]
+    weight: f32,
+}
+
+fn default_symmetry() -> String {
+    "X".to_string()
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
+struct NeighborsDef {
+    #[serde(rename = "neighbor", default)]
+    neighbors: Vec<NeighborDef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NeighborDef {
+    #[serde(rename = "@left")]
+    left: String,
+    #[serde(rename = "@right")]
+    right: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubsetsDef {
+    #[serde(rename = "subset", default)]
+    subsets: Vec<SubsetDef>,
+}
+
+/// Named selection of tiles. Only those tiles and the neighbors between them are used
+#[derive(Debug, Deserialize)]
+struct SubsetDef {
+    #[serde(rename = "@name")]
+    name: String,
+    #[serde(rename = "tile", default)]
+    tiles: Vec<SubsetTileDef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubsetTileDef {
+    #[serde(rename = "@name")]
+    name: String,
+}
+
+/// Names of the tiles in the subset called `name`, checking that they are all defined
+fn subset_tiles<'a>(def: &'a TilesetDefinition, name: &str) -> Result<HashSet<&'a str>> {
+    let subsets = def.subsets.as_ref().map_or(&[][..], |s| &s.subsets[..]);
+    let Some(subset) = subsets.iter().find(|subset| subset.name == name) else {
+        let available: Vec<&str> = subsets.iter().map(|subset| subset.name.as_str()).collect();
+        bail!(
+            "unknown subset '{}' (available: {})",
+            name,
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        );
+    };
+
+    let mut tiles = HashSet::new();
+    for tile in &subset.tiles {
+        if !def.tiles.tiles.iter().any(|t| t.name == tile.name) {
+            bail!("subset '{}' references unknown tile '{}'", name, tile.name);
+        }
+        tiles.insert(tile.name.as_str());
+    }
+    Ok(tiles)
+}
+
+/// Parses a tile reference string like "corner 1" into (tile_name, transform) where transform
+/// is 0-3 for the rotations and 4-7 for the reflections of those rotations
+fn parse_tile_ref(s: &str) -> Result<(&str, usize)> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    match parts.as_slice() {
+        [name] => Ok((name, 0)),
+        [name, transform] => {
+            let transform: usize = transform
+                .parse()
+                .with_context(|| format!("invalid tile reference '{}'", s))?;
+            if transform >= 8 {
+                bail!("invalid tile reference '{}': transform must be 0-7", s);
+            }
+            Ok((name, transform))
+        }
+        _ => bail!("invalid tile reference '{}'", s),
+    }
+}
+
+/// Every orientation of every tile, numbered consecutively per tile.
+/// `transforms[orientation][t]` is the orientation reached by applying transform `t` (as in
+/// [`parse_tile_ref`]) to `orientation`
+struct Orientations {
+    /// first orientation of each tile
+    first: Vec<usize>,
+    transforms: Vec<[usize; 8]>,
+}
+
+impl Orientations {
+    fn new(symmetries: &[Symmetry]) -> Self {
+        let mut first = Vec::with_capacity(symmetries.len());
+        let mut transforms = Vec::new();
+        for &symmetry in symmetries {
+            let offset = transforms.len();
+            first.push(offset);
+            for orientation in 0..symmetry.cardinality() {
+                let mut rotated = [orientation; 4];
+                for r in 1..4 {
+                    rotated[r] = symmetry.rotate(rotated[r - 1]);
+                }
+                let reflected = rotated.map(|o| symmetry.reflect(o));
+                let mut map = [0; 8];
+                map[..4].copy_from_slice(&rotated);
+                map[4..].copy_from_slice(&reflected);
+                transforms.push(map.map(|o| o + offset));
+            }
+        }
+        Self { first, transforms }
+    }
+
+    fn len(&self) -> usize {
+        self.transforms.len()
+    }
+
+    /// orientation of `tile` after applying `transform` to its base orientation
+    fn get(&self, tile: usize, transform: usize) -> usize {
+        self.transforms[self.first[tile]][transform]
+    }
+
+    fn transform(&self, orientation: usize, transform: usize) -> usize {
+        self.transforms[orientation][transform]
+    }
+}
+
+/// Resolution of the bitmaps made from tile images. These only feed the world definition and the
+/// fallback rendering, so they don't need the full image resolution
+const IMAGE_BITMAP_SIZE: usize = 4;
+
+/// Downsamples a tile image to a bitmap, mapping every pixel to the material with the closest
+/// color
+fn bitmap_from_image(img: &DynamicImage, materials: &MaterialRegistry) -> TileBitmap {
+    let size = IMAGE_BITMAP_SIZE as u32;
+    let small = img.resize_exact(size, size, FilterType::Triangle).to_rgb8();
+    (0..size)
+        .map(|y| {
+            (0..size)
+                .map(|x| materials.closest(small.get_pixel(x, y).0))
+                .collect()
+        })
+        .collect()
+}
+
+fn load_image(path: &Path) -> Result<DynamicImage> {
+    image::open(path).with_context(|| format!("Failed to load tile image: {}", path.display()))
+}
+
+/// Parse a tileset from an XML file in the format of mxgmn's WaveFunctionCollapse. Tile images
+/// are loaded from the same directory as the XML file. With a subset given, only the tiles in
+/// that `<subset>` are kept
+pub fn parse_tileset_xml<P: AsRef<Path>>(xml_path: P, subset: Option<&str>) -> Result<Tileset> {
+    let (tileset, invalid_neighbors) = parse_tileset_xml_lenient(xml_path, subset)?;
+    if let Some(problem) = invalid_neighbors.first() {
+        bail!("{}", problem);
+    }
+    Ok(tileset)
+}
+
+/// Like [`parse_tileset_xml`], but neighbor entries that can't be resolved (unknown tiles,
+/// malformed references) are skipped and described in the returned list instead of failing
+pub fn parse_tileset_xml_lenient<P: AsRef<Path>>(
+    xml_path: P,
+    subset: Option<&str>,
+) -> Result<(Tileset, Vec<String>)> {
+    let xml_path = xml_path.as_ref();
+    let xml_content = fs::read_to_string(xml_path)
+        .with_context(|| format!("Failed to read XML file: {}", xml_path.display()))?;
+
+    let def: TilesetDefinition =
+        quick_xml::de::from_str(&xml_content).context("Failed to deserialize XML")?;
+    let unique = def
+        .unique
+        .as_ref()
+        .is_some_and(|unique| unique.eq_ignore_ascii_case("true"));
+
+    let subset = subset.map(|name| subset_tiles(&def, name)).transpose()?;
+    let included = |name: &str| subset.as_ref().is_none_or(|tiles| tiles.contains(name));
+
+    // PNGs are in the same directory as the XML file
+    let base_dir = xml_path.parent().unwrap_or_else(|| Path::new("."));
+
+    // Load tile images
+    let materials = MaterialRegistry::default();
+    let mut tiles = HashMap::new();
+    let mut tile_names = Vec::new();
+    let mut tile_weights = Vec::new();
+    let mut symmetries = Vec::new();
+    let mut tile_size = None;
+    let mut name_to_index = HashMap::new();
+    let tile_defs = def.tiles.tiles.iter().filter(|t| included(&t.name));
+    for (idx, tile_def) in tile_defs.enumerate() {
+        if name_to_index.insert(tile_def.name.as_str(), idx).is_some() {
+            bail!("tile '{}' is defined more than once", tile_def.name);
+        }
+        if !(tile_def.weight.is_finite() && tile_def.weight > 0.0) {
+            bail!(
+                "tile '{}' has weight {}, but weights have to be positive",
+                tile_def.name,
+                tile_def.weight
+            );
+        }
+
+        let symmetry: Symmetry = tile_def
+            .symmetry
+            .parse()
+            .with_context(|| format!("tile '{}'", tile_def.name))?;
+        let orientation_imgs = if unique {
+            (0..symmetry.cardinality())
+                .map(|o| load_image(&base_dir.join(format!("{} {}.png", tile_def.name, o))))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        let img = match orientation_imgs.first() {
+            Some(img) => img.clone(),
+            None => load_image(&base_dir.join(format!("{}.png", tile_def.name)))?,
+        };
+
+        // the renderer blits whole tiles on a grid, so they all need the same square size
+        for img in orientation_imgs.iter().chain([&img]) {
+            let size = tile_size.get_or_insert(img.width());
+            if img.width() != *size || img.height() != *size {
+                bail!(
+                    "tile '{}' image is {}x{}, expected {}x{} like the other tiles",
+                    tile_def.name,
+                    img.width(),
+                    img.height(),
+                    size,
+                    size
+                );
+            }
+        }
+
+        tiles.insert(
+            tile_def.name.clone(),
+            BaseTile {
+                bitmap: bitmap_from_image(&img, &materials),
+                symmetry,
+                img: Some(img),
+                orientation_imgs,
+            },
+        );
+        tile_names.push(tile_def.name.clone());
+        tile_weights.push(tile_def.weight);
+        symmetries.push(symmetry);
+    }
+
+    // dense[d][o1][o2]: orientation o2 can be next to o1 in direction d, with directions
+    // 0: left, 1: down, 2: right, 3: up. Always symmetric: dense[d][o1][o2] == dense[d+2][o2][o1]
+    let orientations = Orientations::new(&symmetries);
+    let num_orientations = orientations.len();
+    let mut dense = vec![vec![vec![false; num_orientations]; num_orientations]; 4];
+
+    // `None` for tiles left out by the subset
+    let lookup = |tile_ref: &str| -> Result<Option<usize>> {
+        let (name, transform) = parse_tile_ref(tile_ref)?;
+        if !def.tiles.tiles.iter().any(|t| t.name == name) {
+            bail!("neighbor references unknown tile '{}'", name);
+        }
+        Ok(name_to_index
+            .get(name)
+            .map(|idx| orientations.get(*idx, transform)))
+    };
+    let mut invalid_neighbors = Vec::new();
+
+    // Each entry says `left` can be directly left of `right`. Rotating both by 90 degrees gives
+    // a vertical pair, and reflecting either pair gives two more
+    for neighbor_def in &def.neighbors.neighbors {
+        let (left, right) = match (lookup(&neighbor_def.left), lookup(&neighbor_def.right)) {
+            (Ok(Some(left)), Ok(Some(right))) => (left, right),
+            (Err(e), _) | (_, Err(e)) => {
+                invalid_neighbors.push(format!(
+                    "neighbor left=\"{}\" right=\"{}\": {:#}",
+                    neighbor_def.left, neighbor_def.right, e
+                ));
+                continue;
+            }
+            _ => continue,
+        };
+        let t = |orientation, transform| orientations.transform(orientation, transform);
+        let down = t(left, 1);
+        let up = t(right, 1);
+
+        let mut allow = |direction: usize, o1: usize, o2: usize| {
+            dense[direction][o1][o2] = true;
+            dense[(direction + 2) % 4][o2][o1] = true;
+        };
+
+        allow(0, right, left);
+        allow(0, t(right, 6), t(left, 6));
+        allow(0, t(left, 4), t(right, 4));
+        allow(0, t(left, 2), t(right, 2));
+
+        allow(1, up, down);
+        allow(1, t(down, 6), t(up, 6));
+        allow(1, t(up, 4), t(down, 4));
+        allow(1, t(down, 2), t(up, 2));
+    }
+
+    // Variants are numbered like the orientations, so they index `dense` directly
+    let variants = tile_variants(&symmetries);
+    debug_assert_eq!(variants.len(), num_orientations);
+    let allowed_neighbors = (0..num_orientations)
+        .map(|o1| {
+            // sides are 0: top, 1: left, 2: bottom, 3: right
+            [0, 1, 2, 3].map(|side| {
+                let direction = (side + 3) % 4;
+                dense[direction][o1].clone()
+            })
+        })
+        .collect();
+
+    let tileset = Tileset {
+        tiles,
+        tile_names,
+        variants,
+        allowed_neighbors,
+        tile_weights,
+        tile_size: IMAGE_BITMAP_SIZE,
+        materials,
+    };
+    Ok((tileset, invalid_neighbors))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn tilemap(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/procgen/tilemaps")
+            .join(name)
+            .join("tileset.xml")
+    }
+
+    #[test]
+    fn tile_refs_parse() {
+        assert_eq!(parse_tile_ref("corner").unwrap(), ("corner", 0));
+        assert_eq!(parse_tile_ref(" corner  5 ").unwrap(), ("corner", 5));
+        for invalid in ["corner 8", "corner x", "corner 1 2", ""] {
+            assert!(parse_tile_ref(invalid).is_err(), "'{}' parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn orientation_transforms_compose() {
+        let symmetries = [
+            Symmetry::X,
+            Symmetry::I,
+            Symmetry::L,
+            Symmetry::T,
+            Symmetry::Backslash,
+            Symmetry::F,
+        ];
+        let orientations = Orientations::new(&symmetries);
+        assert_eq!(orientations.len(), 1 + 2 + 4 + 4 + 2 + 8);
+        for o in 0..orientations.len() {
+            let rotate = |o| orientations.transform(o, 1);
+            let reflect = |o| orientations.transform(o, 4);
+            assert_eq!(rotate(rotate(rotate(rotate(o)))), o);
+            assert_eq!(reflect(reflect(o)), o);
+            assert_eq!(orientations.transform(o, 0), o);
+            // each transform stays within the orientations of the same tile
+            let tile = orientations
+                .first
+                .iter()
+                .rposition(|&first| first <= o)
+                .unwrap();
+            for t in 0..8 {
+                let transformed = orientations.transform(o, t);
+                let tile_of = orientations
+                    .first
+                    .iter()
+                    .rposition(|&first| first <= transformed);
+                assert_eq!(tile_of, Some(tile));
+            }
+        }
+    }
+
+    #[test]
+    fn symmetries_expand_to_their_orientations() {
+        let tileset = parse_tileset_xml(tilemap("Castle"), None).unwrap();
+        for (idx, name) in tileset.tile_names.iter().enumerate() {
+            let variants = tileset
+                .variants
+                .iter()
+                .filter(|v| v.base_tile_idx == idx)
+                .count();
+            assert_eq!(
+                variants,
+                tileset.tiles[name].symmetry.cardinality(),
+                "{}",
+                name
+            );
+        }
+        // 6 I tiles, 3 L tiles, a T tile and an X tile
+        assert_eq!(tileset.variants.len(), 6 * 2 + 3 * 4 + 4 + 1);
+
+        // every neighbor rule holds from both sides
+        let n = tileset.variants.len();
+        for a in 0..n {
+            for side in 0..4 {
+                for b in 0..n {
+                    assert_eq!(
+                        tileset.allowed_neighbors[a][side][b],
+                        tileset.allowed_neighbors[b][(side + 2) % 4][a]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subsets_restrict_tiles_and_neighbors() {
+        let full = parse_tileset_xml(tilemap("knots"), None).unwrap();
+        assert_eq!(full.variants.len(), 4 + 2 + 1 + 2 + 4);
+
+        let subset = parse_tileset_xml(tilemap("knots"), Some("CL")).unwrap();
+        assert_eq!(subset.tile_names, ["corner", "line"]);
+        assert_eq!(subset.tiles.len(), 2);
+        assert_eq!(subset.variants.len(), 4 + 2);
+        assert_eq!(subset.allowed_neighbors.len(), 6);
+
+        // the subset keeps the neighbor rules between its tiles, renumbered
+        let full_option = |option: usize| {
+            let variant = subset.variants[option];
+            let name = &subset.tile_names[variant.base_tile_idx];
+            let base_tile_idx = full.tile_names.iter().position(|n| n == name).unwrap();
+            full.variants
+                .iter()
+                .position(|v| {
+                    (v.base_tile_idx, v.rotation, v.reflected)
+                        == (base_tile_idx, variant.rotation, variant.reflected)
+                })
+                .unwrap()
+        };
+        for a in 0..6 {
+            for side in 0..4 {
+                assert_eq!(subset.allowed_neighbors[a][side].len(), 6);
+                for b in 0..6 {
+                    assert_eq!(
+                        subset.allowed_neighbors[a][side][b],
+                        full.allowed_neighbors[full_option(a)][side][full_option(b)]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_subsets() {
+        let Err(err) = parse_tileset_xml(tilemap("knots"), Some("Sparse")) else {
+            panic!("unknown subset was accepted");
+        };
+        let err = err.to_string();
+        assert!(
+            err.contains("'Sparse'") && err.contains("Standard, Dense"),
+            "{}",
+            err
+        );
+
+        let Err(err) = parse_tileset_xml(tilemap("Castle"), Some("Standard")) else {
+            panic!("subset of a tileset without subsets was accepted");
+        };
+        assert!(err.to_string().contains("available: none"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_weights_that_arent_positive() {
+        // the weight is checked before the tile's image is loaded, so there's no need for one
+        let path = std::env::temp_dir().join(format!("wfc-weight-{}.xml", std::process::id()));
+        fs::write(
+            &path,
+            r#"<set><tiles><tile name="empty" weight="-2"/></tiles><neighbors/></set>"#,
+        )
+        .unwrap();
+        let result = parse_tileset_xml(&path, None);
+        fs::remove_file(&path).unwrap();
+
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("tile 'empty'"), "{}", err);
+    }
+}
@@ -0,0 +1,669 @@
+use std::str::FromStr;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::procgen::{
+    bitset::BitSet,
+    tileset::get_edge,
+    types::Tileset,
+    wfc::{Contradiction, Wave, WaveTile},
+};
+
+/// Global constraint on the road bits of the output, which local adjacency can't express
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoadConstraint {
+    /// every road tile belongs to one connected network
+    Connected,
+
+    /// one connected network without dead ends or forks, i.e. a closed loop. There has to be
+    /// some road
+    Loop,
+}
+
+impl FromStr for RoadConstraint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connected" => Ok(Self::Connected),
+            "loop" => Ok(Self::Loop),
+            _ => Err(anyhow::anyhow!(
+                "unknown road constraint '{}' (expected connected or loop)",
+                s
+            )),
+        }
+    }
+}
+
+/// Options of a tileset with any bits of a road material
+pub fn road_options(tileset: &Tileset) -> BitSet {
+    let mut road = BitSet::empty(tileset.variants.len());
+    for option in 0..tileset.variants.len() {
+        let has_road = tileset
+            .base_tile(option)
+            .bitmap
+            .iter()
+            .flatten()
+            .any(|bit| tileset.materials.is_road(*bit));
+        if has_road {
+            road.insert(option);
+        }
+    }
+    road
+}
+
+/// Where the options of a tileset have road, for enforcing a [`RoadConstraint`]. The road
+/// inside a single tile is assumed to be connected, so tiles only need to be linked through
+/// their edges
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoadNetwork {
+    constraint: RoadConstraint,
+
+    /// options with any road bits
+    road: BitSet,
+
+    /// `exits[side]` holds the options whose road reaches the edge on `side`
+    exits: [BitSet; 4],
+
+    /// road options without exactly two exits, which can't be part of a loop
+    not_loop: BitSet,
+
+    /// road state of the wave as of the last [`Self::update`]. Built from the wave on the next
+    /// prune when missing, e.g. after loading a saved state
+    #[serde(skip)]
+    cache: Option<NetworkCache>,
+}
+
+/// What the remaining options of a wave tile say about its road
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RoadState {
+    /// some option has road
+    may: bool,
+
+    /// every option has road
+    must: bool,
+
+    /// some option has road leaving through each side
+    exits: [bool; 4],
+}
+
+/// Road state of every wave tile and the network the tiles that must have road are part of,
+/// kept up to date tile by tile so pruning only has to look at what changed
+#[derive(Debug, Clone)]
+struct NetworkCache {
+    states: Vec<RoadState>,
+
+    /// number of tiles that may have road
+    may: usize,
+
+    /// number of tiles that must have road
+    must: usize,
+
+    /// tiles linked to the tiles that must have road through edges that may carry road on both
+    /// sides. `None` until it's flooded, which has to happen again whenever a tile gains road
+    /// (only undoing does that), since the network can then grow
+    network: Option<Vec<bool>>,
+
+    /// edges that stopped carrying road since the last prune, as (tile index, side)
+    cut: Vec<(usize, u8)>,
+
+    /// tiles that must have road since the last prune
+    new_must: Vec<usize>,
+
+    /// marks of [`Self::split`], stamped so they never need clearing
+    marks: Vec<u32>,
+    stamp: u32,
+}
+
+impl RoadNetwork {
+    /// Errors if no option of the tileset has road, since there would be no network to constrain
+    pub fn new(tileset: &Tileset, constraint: RoadConstraint) -> Result<Self, Error> {
+        let num_options = tileset.variants.len();
+        let road = road_options(tileset);
+        if road.is_empty() {
+            return Err(anyhow::anyhow!(
+                "road constraint needs tiles with road, but the tileset has no road material"
+            ));
+        }
+        let mut exits = [0; 4].map(|_| BitSet::empty(num_options));
+        for (option, tile) in tileset.variants.iter().enumerate() {
+            let bitmap = tileset
+                .base_tile(option)
+                .transformed_bitmap(tile.rotation, tile.reflected);
+            for (side, exits) in exits.iter_mut().enumerate() {
+                if get_edge(&bitmap, side)
+                    .iter()
+                    .any(|bit| tileset.materials.is_road(*bit))
+                {
+                    exits.insert(option);
+                }
+            }
+        }
+
+        let mut not_loop = BitSet::empty(num_options);
+        for option in road.iter() {
+            if exits.iter().filter(|e| e.contains(option)).count() != 2 {
+                not_loop.insert(option);
+            }
+        }
+
+        Ok(Self {
+            constraint,
+            road,
+            exits,
+            not_loop,
+            cache: None,
+        })
+    }
+
+    pub fn constraint(&self) -> RoadConstraint {
+        self.constraint
+    }
+
+    /// Removes the options that can never be part of the network from every unobserved tile:
+    /// for loops, dead ends, forks and roads running off the edge of non-periodic output
+    pub fn restrict(&self, wave: &mut Wave) -> Result<(), Contradiction> {
+        if self.constraint != RoadConstraint::Loop {
+            return Ok(());
+        }
+
+        for y in 0..wave.height {
+            for x in 0..wave.width {
+                let mut banned = self.not_loop.clone();
+                for (side, exits) in self.exits.iter().enumerate() {
+                    if wave.neighbor(x, y, side as u8).is_none() {
+                        banned.union_with(exits);
+                    }
+                }
+
+                if let WaveTile::Unobserved(options) = &mut wave.tiles[y * wave.width + x] {
+                    for option in banned.iter() {
+                        options.remove(option);
+                    }
+                    if options.is_empty() {
+                        return Err(Contradiction {
+                            x,
+                            y,
+                            neighbor: None,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self, tile: &WaveTile) -> RoadState {
+        match tile {
+            WaveTile::Observed(option) => {
+                let road = self.road.contains(*option);
+                RoadState {
+                    may: road,
+                    must: road,
+                    exits: self.exits.each_ref().map(|exits| exits.contains(*option)),
+                }
+            }
+            WaveTile::Unobserved(options) => {
+                let with_road = options.intersection_count(&self.road);
+                RoadState {
+                    may: with_road > 0,
+                    must: with_road == options.count(),
+                    exits: self
+                        .exits
+                        .each_ref()
+                        .map(|exits| options.intersection_count(exits) > 0),
+                }
+            }
+        }
+    }
+
+    /// Takes note that the options of wave tile `idx` changed. Every change has to be reported
+    /// before the next [`Self::prune`]
+    pub fn update(&mut self, wave: &Wave, idx: usize) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let (old, new) = (cache.states[idx], self.state(&wave.tiles[idx]));
+        if old == new {
+            return;
+        }
+
+        let cache = self.cache.as_mut().expect("cache was just there");
+        cache.states[idx] = new;
+        cache.may = cache.may + new.may as usize - old.may as usize;
+        cache.must = cache.must + new.must as usize - old.must as usize;
+        if new.must && !old.must {
+            cache.new_must.push(idx);
+        }
+        let gained =
+            (new.may && !old.may) || (0..4).any(|side| new.exits[side] && !old.exits[side]);
+        if gained {
+            cache.network = None;
+        }
+        for side in 0..4 {
+            if old.exits[side] && !new.exits[side] {
+                cache.cut.push((idx, side as u8));
+            }
+        }
+    }
+
+    /// Checks whether every tile that must have road can still reach the others through tiles
+    /// that may have road. Returns the road options of tiles that can't reach them anymore as
+    /// (tile index, option), which have to be removed. `(x, y)` is the tile that last changed,
+    /// blamed if a loop has no way left to get any road at all. Only the tiles passed to
+    /// [`Self::update`] since the last prune are looked at again
+    pub fn prune(
+        &mut self,
+        wave: &Wave,
+        x: usize,
+        y: usize,
+    ) -> Result<Vec<(usize, usize)>, Contradiction> {
+        if self.cache.is_none() {
+            let states = wave.tiles.iter().map(|tile| self.state(tile)).collect();
+            self.cache = Some(NetworkCache::new(states));
+        }
+        let cache = self.cache.as_mut().expect("cache was just built");
+
+        if cache.must == 0 {
+            cache.network = None;
+            cache.cut.clear();
+            cache.new_must.clear();
+            if self.constraint == RoadConstraint::Loop && cache.may == 0 {
+                return Err(Contradiction {
+                    x,
+                    y,
+                    neighbor: None,
+                });
+            }
+            // the network could still end up anywhere
+            return Ok(Vec::new());
+        }
+
+        let left_out = match cache.shrink(wave) {
+            Some(left_out) => left_out,
+            None => cache.flood(wave)?,
+        };
+
+        let mut removals = Vec::new();
+        for idx in left_out {
+            if let WaveTile::Unobserved(options) = &wave.tiles[idx] {
+                removals.extend(
+                    options
+                        .iter()
+                        .filter(|option| self.road.contains(*option))
+                        .map(|option| (idx, option)),
+                );
+            }
+        }
+        Ok(removals)
+    }
+}
+
+impl NetworkCache {
+    fn new(states: Vec<RoadState>) -> Self {
+        let num_tiles = states.len();
+        Self {
+            may: states.iter().filter(|state| state.may).count(),
+            must: states.iter().filter(|state| state.must).count(),
+            states,
+            network: None,
+            cut: Vec::new(),
+            new_must: Vec::new(),
+            marks: vec![0; num_tiles],
+            stamp: 0,
+        }
+    }
+
+    /// Neighbor of tile `idx` on `side` if the edge between them may carry road
+    fn linked(&self, wave: &Wave, idx: usize, side: u8) -> Option<usize> {
+        let (nx, ny) = wave.neighbor(idx % wave.width, idx / wave.width, side)?;
+        let neighbor = ny * wave.width + nx;
+        let connects = self.states[idx].exits[side as usize]
+            && self.states[neighbor].exits[(side as usize + 2) % 4];
+        connects.then_some(neighbor)
+    }
+
+    /// `count` fresh marks for searches, starting at the returned one, so marks of earlier
+    /// searches don't need clearing
+    fn next_marks(&mut self, count: u32) -> u32 {
+        if self.stamp > u32::MAX - count {
+            self.marks.fill(0);
+            self.stamp = 0;
+        }
+        let first = self.stamp + 1;
+        self.stamp += count;
+        first
+    }
+
+    /// Tiles reachable from `start` through edges that may carry road, in the order found
+    fn reach(&mut self, wave: &Wave, start: usize) -> Vec<usize> {
+        let mark = self.next_marks(1);
+        self.marks[start] = mark;
+        let mut tiles = vec![start];
+        let mut next = 0;
+        while let Some(&idx) = tiles.get(next) {
+            next += 1;
+            for side in 0..4 {
+                if let Some(neighbor) = self.linked(wave, idx, side)
+                    && self.marks[neighbor] != mark
+                {
+                    self.marks[neighbor] = mark;
+                    tiles.push(neighbor);
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Floods the network from the first tile that must have road. Returns the tiles that may
+    /// have road but were left out, or the first one left out that must have road
+    fn flood(&mut self, wave: &Wave) -> Result<Vec<usize>, Contradiction> {
+        self.cut.clear();
+        self.new_must.clear();
+        let start = self
+            .states
+            .iter()
+            .position(|state| state.must)
+            .expect("some tile must have road");
+        let mut network = vec![false; self.states.len()];
+        for idx in self.reach(wave, start) {
+            network[idx] = true;
+        }
+
+        let mut left_out = Vec::new();
+        for (idx, state) in self.states.iter().enumerate() {
+            if network[idx] || !state.may {
+                continue;
+            }
+            if state.must {
+                // the network is only kept while it's consistent with the wave
+                self.network = None;
+                return Err(Contradiction {
+                    x: idx % wave.width,
+                    y: idx / wave.width,
+                    neighbor: None,
+                });
+            }
+            left_out.push(idx);
+        }
+        self.network = Some(network);
+        Ok(left_out)
+    }
+
+    /// Takes the edges cut since the last prune out of the network. Returns the tiles that fell
+    /// out of it, sorted, or `None` if the network has to be flooded again: it's missing, or
+    /// tiles that must have road ended up apart
+    fn shrink(&mut self, wave: &Wave) -> Option<Vec<usize>> {
+        let mut network = self.network.take()?;
+        let new_must = std::mem::take(&mut self.new_must);
+        if new_must.iter().any(|&idx| !network[idx]) {
+            return None;
+        }
+
+        // the network can only have come apart between the ends of the cut edges
+        let mut ends = Vec::new();
+        for (idx, side) in std::mem::take(&mut self.cut) {
+            let Some((nx, ny)) = wave.neighbor(idx % wave.width, idx / wave.width, side) else {
+                continue;
+            };
+            let neighbor = ny * wave.width + nx;
+            if network[idx] && network[neighbor] && self.linked(wave, idx, side).is_none() {
+                ends.extend([idx, neighbor]);
+            }
+        }
+        ends.sort_unstable();
+        ends.dedup();
+
+        let (parts, rest) = self.split(wave, &ends);
+        let must_in = |tiles: &[usize]| tiles.iter().filter(|&&idx| self.states[idx].must).count();
+        let part_must: Vec<usize> = parts.iter().map(|part| must_in(part)).collect();
+        let rest_must = self.must - part_must.iter().sum::<usize>();
+        let keepers = part_must.iter().filter(|&&must| must > 0).count() + (rest_must > 0) as usize;
+        if keepers > 1 {
+            return None;
+        }
+
+        // every tile that must have road is in a single part, everything else falls out
+        let mut left_out = Vec::new();
+        for (part, must) in parts.into_iter().zip(part_must) {
+            if must == 0 {
+                left_out.extend(part);
+            }
+        }
+        if rest_must == 0
+            && let Some(rest) = rest
+        {
+            left_out.extend(self.reach(wave, rest));
+        }
+        for &idx in &left_out {
+            network[idx] = false;
+        }
+
+        self.network = Some(network);
+        left_out.sort_unstable();
+        Some(left_out)
+    }
+
+    /// Finds out which of the `ends` are still linked, by searching from all of them at once.
+    /// Searches that meet carry on together, until all but one group of them ran out of tiles.
+    /// Returns the parts of the network found by the groups that ran out, and an end the last
+    /// group started from if it didn't. Takes about as long as the parts that ran out are big
+    fn split(&mut self, wave: &Wave, ends: &[usize]) -> (Vec<Vec<usize>>, Option<usize>) {
+        if ends.len() < 2 {
+            return (Vec::new(), ends.first().copied());
+        }
+
+        // one mark per search, and the search each one has joined
+        let first = self.next_marks(ends.len() as u32);
+        let mut group: Vec<usize> = (0..ends.len()).collect();
+        let root = |group: &[usize], mut search: usize| {
+            while group[search] != search {
+                search = group[search];
+            }
+            search
+        };
+        let mut searches: Vec<(Vec<usize>, usize)> = ends
+            .iter()
+            .enumerate()
+            .map(|(search, &end)| {
+                self.marks[end] = first + search as u32;
+                (vec![end], 0)
+            })
+            .collect();
+
+        let mut parts = Vec::new();
+        let mut done = vec![false; ends.len()];
+        loop {
+            for (search, (found, next)) in searches.iter_mut().enumerate() {
+                let Some(&idx) = found.get(*next) else {
+                    continue;
+                };
+                *next += 1;
+                for side in 0..4 {
+                    let Some(neighbor) = self.linked(wave, idx, side) else {
+                        continue;
+                    };
+                    let mark = self.marks[neighbor];
+                    if (first..first + ends.len() as u32).contains(&mark) {
+                        let (a, b) = (root(&group, search), root(&group, (mark - first) as usize));
+                        group[a.max(b)] = a.min(b);
+                    } else {
+                        self.marks[neighbor] = first + search as u32;
+                        found.push(neighbor);
+                    }
+                }
+            }
+
+            // groups whose searches all ran out found a whole part of the network
+            let roots: Vec<usize> = (0..searches.len())
+                .map(|search| root(&group, search))
+                .collect();
+            let mut running = vec![false; searches.len()];
+            for (search, (found, next)) in searches.iter().enumerate() {
+                running[roots[search]] |= *next < found.len();
+            }
+            let mut live = Vec::new();
+            for group_root in 0..searches.len() {
+                if roots[group_root] != group_root || done[group_root] {
+                    continue;
+                }
+                if running[group_root] {
+                    live.push(group_root);
+                    continue;
+                }
+                done[group_root] = true;
+                let part = (0..searches.len())
+                    .filter(|&search| roots[search] == group_root)
+                    .flat_map(|search| std::mem::take(&mut searches[search].0))
+                    .collect();
+                parts.push(part);
+            }
+            match live[..] {
+                [] => return (parts, None),
+                [last] => return (parts, Some(ends[last])),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procgen::{
+        BacktrackConfig, Generator, Pin, WfcConfig, json_tileset::parse_tileset_json_str,
+        tileset::make_island_race_tileset, wfc::WaveFunctionCollapse,
+    };
+
+    #[test]
+    fn island_roads_form_a_loop() {
+        let tileset = make_island_race_tileset();
+        let mut network = RoadNetwork::new(&tileset, RoadConstraint::Loop).unwrap();
+        let generator = Generator {
+            tileset,
+            width: 8,
+            height: 8,
+            config: WfcConfig {
+                backtracking: Some(BacktrackConfig::default()),
+                pins: vec![Pin {
+                    x: 4,
+                    y: 4,
+                    tile: "road_straight".to_string(),
+                    rotation: None,
+                }],
+                roads: Some(RoadConstraint::Loop),
+                ..WfcConfig::default()
+            },
+            max_attempts: 10,
+        };
+        let (wfc, _) = generator.run(0, &mut ()).unwrap();
+
+        // every road tile has two exits, and they all connect into a single network
+        let wave = &wfc.wave;
+        let states: Vec<RoadState> = wave.tiles.iter().map(|t| network.state(t)).collect();
+        let road_tiles = states.iter().filter(|state| state.must).count();
+        assert!(road_tiles >= 4);
+        for state in states.iter().filter(|state| state.must) {
+            assert_eq!(state.exits.iter().filter(|exit| **exit).count(), 2);
+        }
+        assert_eq!(network.prune(wave, 0, 0), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn pruning_as_tiles_change_matches_pruning_from_scratch() {
+        let tileset = make_island_race_tileset();
+        for constraint in [RoadConstraint::Connected, RoadConstraint::Loop] {
+            for seed in 0..4 {
+                let config = WfcConfig {
+                    backtracking: Some(BacktrackConfig::default()),
+                    roads: Some(constraint),
+                    ..WfcConfig::default()
+                };
+                let Ok(mut wfc) = WaveFunctionCollapse::new(tileset.clone(), 12, 10, seed, config)
+                else {
+                    continue;
+                };
+                while !wfc.is_finished() && wfc.step(&mut ()).is_ok() {
+                    // nothing the network kept up with should be left to remove
+                    let mut network = RoadNetwork::new(&tileset, constraint).unwrap();
+                    assert_eq!(
+                        network.prune(&wfc.wave, 0, 0),
+                        Ok(Vec::new()),
+                        "{:?}, seed {}, step {}",
+                        constraint,
+                        seed,
+                        wfc.steps()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cut_off_road_falls_out_of_the_network() {
+        let json = r#"{
+            "legend": { "R": "road", "G": "grass" },
+            "tiles": [
+                { "name": "cross", "bitmap": ["GRG", "RRR", "GRG"] },
+                { "name": "grass", "bitmap": ["GGG", "GGG", "GGG"] }
+            ]
+        }"#;
+        let tileset = parse_tileset_json_str(json).unwrap();
+        let [cross, grass] = [0, 1];
+        let mut wave = Wave {
+            tiles: vec![WaveTile::Unobserved(BitSet::full(2)); 5],
+            width: 5,
+            height: 1,
+            periodic: false,
+        };
+        wave.tiles[0] = WaveTile::Observed(cross);
+        let mut network = RoadNetwork::new(&tileset, RoadConstraint::Connected).unwrap();
+        assert_eq!(network.prune(&wave, 0, 0), Ok(Vec::new()));
+
+        // grass in the middle cuts the last two tiles off
+        wave.tiles[2] = WaveTile::Observed(grass);
+        network.update(&wave, 2);
+        assert_eq!(network.prune(&wave, 2, 0), Ok(vec![(3, cross), (4, cross)]));
+
+        // road that can't be reached anymore
+        wave.tiles[4] = WaveTile::Observed(cross);
+        network.update(&wave, 4);
+        assert_eq!(
+            network.prune(&wave, 4, 0),
+            Err(Contradiction {
+                x: 4,
+                y: 0,
+                neighbor: None
+            })
+        );
+
+        // taking the grass back links everything up again
+        wave.tiles[2] = WaveTile::Unobserved(BitSet::full(2));
+        network.update(&wave, 2);
+        assert_eq!(network.prune(&wave, 2, 0), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn road_comes_from_the_materials() {
+        let json = r#"{
+            "materials": [{ "name": "track", "color": [0.5, 0.5, 0.5, 1.0], "road": true }],
+            "legend": { "T": "track", "R": "road", "G": "grass" },
+            "tiles": [
+                { "name": "grass", "bitmap": ["GG", "GG"] },
+                { "name": "track", "bitmap": ["TT", "TT"] },
+                { "name": "painted", "bitmap": ["RR", "RR"] }
+            ]
+        }"#;
+        let tileset = parse_tileset_json_str(json).unwrap();
+        let road = road_options(&tileset);
+        assert_eq!(road.iter().collect::<Vec<_>>(), vec![1, 2]);
+
+        let json = r#"{
+            "legend": { "G": "grass" },
+            "tiles": [{ "name": "grass", "bitmap": ["GG", "GG"] }]
+        }"#;
+        let tileset = parse_tileset_json_str(json).unwrap();
+        assert!(RoadNetwork::new(&tileset, RoadConstraint::Connected).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::procgen::{
+    bitset::BitSet,
+    wfc::{Contradiction, Wave, WaveTile},
+};
+
+/// AC-4 style propagation state: for every tile, side and option, the number of options on the
+/// neighbor at that side that still allow the option. An option is removed once any of its
+/// counts drops to 0, so only removals are ever propagated
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupportCounts {
+    /// `reverse[option][side]` holds every option that allows `option` when placed on `side`
+    /// of it
+    reverse: Vec<[BitSet; 4]>,
+
+    /// indexed by `(tile * 4 + side) * num_options + option`
+    counts: Vec<u32>,
+    num_options: usize,
+}
+
+impl SupportCounts {
+    /// `masks[option][side]` holds every option allowed on `side` of `option`
+    pub fn new(masks: &[[BitSet; 4]], wave: &Wave) -> Self {
+        let num_options = masks.len();
+        let mut reverse = vec![
+            [
+                BitSet::empty(num_options),
+                BitSet::empty(num_options),
+                BitSet::empty(num_options),
+                BitSet::empty(num_options),
+            ];
+            num_options
+        ];
+        for (neighbor_opt, sides) in masks.iter().enumerate() {
+            for (side, allowed) in sides.iter().enumerate() {
+                // neighbor_opt sits on the opposite side of every option it allows
+                for option in allowed.iter() {
+                    reverse[option][(side + 2) % 4].insert(neighbor_opt);
+                }
+            }
+        }
+
+        let mut supports = Self {
+            reverse,
+            counts: vec![0; wave.tiles.len() * 4 * num_options],
+            num_options,
+        };
+        supports.recount(wave);
+        supports
+    }
+
+    /// Recomputes every count from scratch
+    fn recount(&mut self, wave: &Wave) {
+        let mut domain = BitSet::empty(self.num_options);
+        for y in 0..wave.height {
+            for x in 0..wave.width {
+                let tile_idx = y * wave.width + x;
+                for side in 0..4 {
+                    let Some((nx, ny)) = wave.neighbor(x, y, side) else {
+                        continue;
+                    };
+                    domain.clear();
+                    match wave.get(nx, ny) {
+                        WaveTile::Observed(i) => domain.insert(*i),
+                        WaveTile::Unobserved(options) => domain.union_with(options),
+                    }
+
+                    let start = (tile_idx * 4 + side as usize) * self.num_options;
+                    for option in 0..self.num_options {
+                        self.counts[start + option] =
+                            self.reverse[option][side as usize].intersection_count(&domain) as u32;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Options of unobserved tiles that have no support on some side, as (tile index, option).
+    /// These have to be removed before the counts are consistent with the wave
+    pub fn unsupported(&self, wave: &Wave) -> Vec<(usize, usize)> {
+        let mut unsupported = Vec::new();
+        for y in 0..wave.height {
+            for x in 0..wave.width {
+                let tile_idx = y * wave.width + x;
+                let WaveTile::Unobserved(options) = wave.get(x, y) else {
+                    continue;
+                };
+                for option in options.iter() {
+                    let is_unsupported = (0..4).any(|side| {
+                        wave.neighbor(x, y, side).is_some()
+                            && self.counts
+                                [(tile_idx * 4 + side as usize) * self.num_options + option]
+                                == 0
+                    });
+                    if is_unsupported {
+                        unsupported.push((tile_idx, option));
+                    }
+                }
+            }
+        }
+        unsupported
+    }
+
+    /// Removes each (tile index, option) from the wave (if still there) and propagates until no
+    /// more options lose their support. `on_remove` is called with the tile index and option of
+    /// every option taken from an unobserved tile. Only options that were still there count as
+    /// removed, so stale or repeated removals are ignored. After a contradiction no more options
+    /// are removed, but the counts still catch up with every removal that was made, so that
+    /// [`Self::restore`] can undo them exactly
+    pub fn propagate(
+        &mut self,
+        masks: &[[BitSet; 4]],
+        wave: &mut Wave,
+        removals: Vec<(usize, usize)>,
+        mut on_remove: impl FnMut(usize, usize),
+    ) -> Result<(), Contradiction> {
+        let mut contradiction = None;
+        let mut queue = Vec::with_capacity(removals.len());
+        for (tile_idx, option) in removals {
+            let WaveTile::Unobserved(options) = &mut wave.tiles[tile_idx] else {
+                continue;
+            };
+            if !options.contains(option) {
+                continue;
+            }
+
+            options.remove(option);
+            on_remove(tile_idx, option);
+            if options.is_empty() && contradiction.is_none() {
+                contradiction = Some(Contradiction {
+                    x: tile_idx % wave.width,
+                    y: tile_idx / wave.width,
+                    neighbor: None,
+                });
+            }
+            queue.push((tile_idx, option));
+        }
+        self.drain(masks, wave, queue, contradiction, on_remove)
+    }
+
+    /// Propagates the `removed` options of tile `tile_idx`, which was just observed. The wave
+    /// already has to hold the observation, and `removed` has to be the options the tile had
+    /// besides it
+    pub fn propagate_observed(
+        &mut self,
+        masks: &[[BitSet; 4]],
+        wave: &mut Wave,
+        tile_idx: usize,
+        removed: &BitSet,
+        on_remove: impl FnMut(usize, usize),
+    ) -> Result<(), Contradiction> {
+        debug_assert!(
+            matches!(wave.tiles[tile_idx], WaveTile::Observed(option) if !removed.contains(option)),
+            "only observed tiles lose options outside of propagation"
+        );
+        let queue = removed.iter().map(|option| (tile_idx, option)).collect();
+        self.drain(masks, wave, queue, None, on_remove)
+    }
+
+    /// Takes the support of every queued (tile index, option) away from its neighbors, removing
+    /// and queueing the options left without support
+    fn drain(
+        &mut self,
+        masks: &[[BitSet; 4]],
+        wave: &mut Wave,
+        mut queue: Vec<(usize, usize)>,
+        mut contradiction: Option<Contradiction>,
+        mut on_remove: impl FnMut(usize, usize),
+    ) -> Result<(), Contradiction> {
+        while let Some((tile_idx, removed)) = queue.pop() {
+            let (x, y) = (tile_idx % wave.width, tile_idx / wave.width);
+            for side in 0..4 {
+                let Some((child_x, child_y)) = wave.neighbor(x, y, side) else {
+                    continue;
+                };
+                let child_idx = child_y * wave.width + child_x;
+
+                // the child sees this tile on the opposite side
+                let start = (child_idx * 4 + (side as usize + 2) % 4) * self.num_options;
+                for option in masks[removed][side as usize].iter() {
+                    let count = &mut self.counts[start + option];
+                    *count -= 1;
+                    if *count > 0 || contradiction.is_some() {
+                        continue;
+                    }
+
+                    if let WaveTile::Unobserved(options) = &mut wave.tiles[child_idx]
+                        && options.contains(option)
+                    {
+                        options.remove(option);
+                        on_remove(child_idx, option);
+                        if options.is_empty() {
+                            contradiction = Some(Contradiction {
+                                x: child_x,
+                                y: child_y,
+                                neighbor: Some((x, y)),
+                            });
+                        }
+                        queue.push((child_idx, option));
+                    }
+                }
+            }
+        }
+
+        match contradiction {
+            Some(contradiction) => Err(contradiction),
+            None => Ok(()),
+        }
+    }
+
+    /// Undoes the counts of a removal made by [`Self::propagate`], after `option` was put back
+    /// on tile `tile_idx`
+    pub fn restore(&mut self, masks: &[[BitSet; 4]], wave: &Wave, tile_idx: usize, option: usize) {
+        let (x, y) = (tile_idx % wave.width, tile_idx / wave.width);
+        for side in 0..4 {
+            let Some((child_x, child_y)) = wave.neighbor(x, y, side) else {
+                continue;
+            };
+            let child_idx = child_y * wave.width + child_x;
+            let start = (child_idx * 4 + (side as usize + 2) % 4) * self.num_options;
+            for supported in masks[option][side as usize].iter() {
+                self.counts[start + supported] += 1;
+            }
+        }
+    }
+}
@@ -1,108 +1,29 @@
 use std::collections::HashMap;
 
-use crate::procgen::types::{BaseTile, Bit, TILE_SIZE, Tileset};
-
-const R: Bit = Bit::Road;
-const S: Bit = Bit::Space;
-const G: Bit = Bit::Grass;
-const D: Bit = Bit::Dirt;
-
-#[rustfmt::skip]
-pub const ROAD_STRAIGHT: BaseTile = BaseTile {
-    bitmap: [
-        [G, R, R, G], 
-        [G, R, R, G], 
-        [G, R, R, G], 
-        [G, R, R, G]
-    ],
-};
-
-#[rustfmt::skip]
-pub const ROAD_TURN: BaseTile = BaseTile {
-    bitmap: [
-        [G, R, R, G], 
-        [G, R, R, R], 
-        [G, R, R, R], 
-        [G, G, G, G]
-    ],
-};
-
-#[rustfmt::skip]
-pub const ROAD_END: BaseTile = BaseTile {
-    bitmap: [
-        [G, G, G, G], 
-        [G, R, R, G], 
-        [G, R, R, G], 
-        [G, R, R, G]
-    ],
-};
-
-#[rustfmt::skip]
-pub const PURE_SPACE: BaseTile = BaseTile {
-    bitmap: [
-        [S, S, S, S], 
-        [S, S, S, S], 
-        [S, S, S, S], 
-        [S, S, S, S]
-    ],
-};
-
-#[rustfmt::skip]
-pub const PURE_GRASS: BaseTile = BaseTile {
-    bitmap: [
-        [G, G, G, G], 
-        [G, G, G, G], 
-        [G, G, G, G], 
-        [G, G, G, G]
-    ],
-};
-
-#[rustfmt::skip]
-pub const ISLAND_EDGE: BaseTile = BaseTile {
-    bitmap: [
-        [G, G, G, G], 
-        [G, G, G, G], 
-        [D, D, D, D], 
-        [S, S, S, S]
-    ],
-};
-
-#[rustfmt::skip]
-pub const ISLAND_CORNER: BaseTile = BaseTile {
-    bitmap: [
-        [G, G, D, S], 
-        [G, G, D, S], 
-        [D, D, S, S], 
-        [S, S, S, S]
-    ],
-};
+use anyhow::{Result, bail};
 
-#[rustfmt::skip]
-pub const ISLAND_INNER: BaseTile = BaseTile {
-    bitmap: [
-        [G, G, G, G], 
-        [G, G, G, G], 
-        [G, G, G, D], 
-        [G, G, D, S]
-    ],
+use crate::procgen::{
+    json_tileset::parse_tileset_json_str,
+    material::{MaterialId, MaterialRegistry},
+    roads::RoadConstraint,
+    types::{BaseTile, Symmetry, Tile, TileBitmap, Tileset, tile_variants},
+    wfc::Pin,
 };
 
 /// Each edge is read in CCW direction around the tile
-fn get_edge(tile: &BaseTile, side: usize) -> Vec<Bit> {
+pub fn get_edge(bitmap: &TileBitmap, side: usize) -> Vec<MaterialId> {
+    let size = bitmap.len();
     match side {
-        0 => tile.bitmap[0].iter().rev().cloned().collect(), // top: right to left
-        1 => (0..TILE_SIZE).map(|i| tile.bitmap[i][0]).collect(), // left: top to bottom
-        2 => tile.bitmap[TILE_SIZE - 1].iter().cloned().collect(), // bottom: left to right
-        _ => (0..TILE_SIZE)
-            .rev()
-            .map(|i| tile.bitmap[i][TILE_SIZE - 1])
-            .collect(), // right: bottom to top
+        0 => bitmap[0].iter().rev().cloned().collect(), // top: right to left
+        1 => bitmap.iter().map(|row| row[0]).collect(), // left: top to bottom
+        2 => bitmap[size - 1].clone(),                  // bottom: left to right
+        _ => bitmap.iter().rev().map(|row| row[size - 1]).collect(), // right: bottom to top
     }
 }
 
-fn edges_match(tile1: &BaseTile, side1: usize, tile2: &BaseTile, side2: usize) -> bool {
-    let bits1 = get_edge(tile1, side1);
-    let mut bits2 = get_edge(tile2, side2);
+fn edges_match(bitmap1: &TileBitmap, side1: usize, bitmap2: &TileBitmap, side2: usize) -> bool {
+    let bits1 = get_edge(bitmap1, side1);
+    let mut bits2 = get_edge(bitmap2, side2);
 
     bits2.reverse();
 
@@ -110,65 +31,306 @@ fn edges_match(tile1: &BaseTile, side1: usize, tile2: &BaseTile, side2: usize) -
 }
 
 pub fn make_island_race_tileset() -> Tileset {
-    // (tile, name, weight)
-    let tile_defs: Vec<(BaseTile, &str, f32)> = vec![
-        (ROAD_STRAIGHT, "road_straight", 1.0),
-        (ROAD_TURN, "road_turn", 0.5),
-        (ROAD_END, "road_end", 0.01),
-        (PURE_SPACE, "pure_space", 10.0),
-        (PURE_GRASS, "pure_grass", 2.0),
-        (ISLAND_EDGE, "island_edge", 1.0),
-        (ISLAND_CORNER, "island_corner", 1.0),
-        (ISLAND_INNER, "island_inner", 1.0),
-    ];
-
-    let tiles: Vec<BaseTile> = tile_defs.iter().map(|(t, _, _)| t.clone()).collect();
-    let tile_names: Vec<String> = tile_defs.iter().map(|(_, n, _)| n.to_string()).collect();
-    let tile_weights: Vec<f32> = tile_defs.iter().map(|(_, _, w)| *w).collect();
+    // Weights are per orientation, so symmetric tiles with fewer distinct orientations get a
+    // higher weight to keep the same overall frequency
+    parse_tileset_json_str(include_str!("tilemaps/island_race.json"))
+        .expect("built-in island race tileset is valid")
+}
 
-    let tile_map = HashMap::from_iter(tile_names.clone().into_iter().zip(tiles.clone()));
+/// Builds a tileset from (name, tile, weight) triples, allowing two tiles next to each other
+/// wherever their touching edges have the same bits. Every bitmap must be `tile_size` x
+/// `tile_size`, using materials from `materials`
+pub fn edge_matched_tileset(
+    tile_size: usize,
+    materials: MaterialRegistry,
+    tile_defs: Vec<(String, BaseTile, f32)>,
+) -> Tileset {
+    build_tileset(tile_size, materials, tile_defs, |tiles, variants| {
+        // produce neighbors via matching edges of every distinct orientation
+        let bitmaps: Vec<TileBitmap> = variants
+            .iter()
+            .map(|v| tiles[v.base_tile_idx].transformed_bitmap(v.rotation, v.reflected))
+            .collect();
+        bitmaps
+            .iter()
+            .map(|bitmap1| {
+                [0, 1, 2, 3].map(|side| {
+                    bitmaps
+                        .iter()
+                        .map(|bitmap2| edges_match(bitmap1, side, bitmap2, (side + 2) % 4))
+                        .collect()
+                })
+            })
+            .collect()
+    })
+}
 
-    // produce neighbors via matching edges
-    let num_tiles = tiles.len();
-    let num_rotated_tiles = num_tiles * 4;
-    let mut allowed_neighbors = Vec::with_capacity(num_rotated_tiles);
+/// Edge labels of a tile in its base orientation, indexed by side (top, left, bottom, right).
+/// Like [`get_edge`], a label describes its edge read counterclockwise around the tile
+pub type TileSockets = [String; 4];
 
-    for _ in 0..num_rotated_tiles {
-        allowed_neighbors.push([
-            vec![false; num_rotated_tiles],
-            vec![false; num_rotated_tiles],
-            vec![false; num_rotated_tiles],
-            vec![false; num_rotated_tiles],
-        ]);
-    }
+/// Which socket labels are mirror images of each other. Two edges connect if one's label is the
+/// mirror of the other's, since touching edges are read in opposite directions. Labels without a
+/// mirror are symmetric and connect to themselves
+#[derive(Debug, Clone, Default)]
+pub struct SocketMirrors {
+    mirrors: HashMap<String, String>,
+}
 
-    for tile_one_rot_idx in 0..num_rotated_tiles {
-        for side in 0..4 {
-            for tile_two_rot_idx in 0..num_rotated_tiles {
-                let tile_one_idx = tile_one_rot_idx / 4;
-                let tile_one_rot = tile_one_rot_idx % 4;
-                let tile_two_idx = tile_two_rot_idx / 4;
-                let tile_two_rot = tile_two_rot_idx % 4;
+impl SocketMirrors {
+    /// Errors if a label is paired with itself or shows up in more than one pair
+    pub fn new(pairs: &[(String, String)]) -> Result<Self> {
+        let mut mirrors = HashMap::new();
+        for (a, b) in pairs {
+            if a == b {
+                bail!("socket '{}' can't be its own mirror", a);
+            }
+            for label in [a, b] {
+                if mirrors.contains_key(label) {
+                    bail!("socket '{}' is in more than one mirror pair", label);
+                }
+            }
+            mirrors.insert(a.clone(), b.clone());
+            mirrors.insert(b.clone(), a.clone());
+        }
+        Ok(Self { mirrors })
+    }
 
-                let tile1 = &tiles[tile_one_idx];
-                let tile2 = &tiles[tile_two_idx];
+    pub fn mirror<'a>(&'a self, label: &'a str) -> &'a str {
+        self.mirrors.get(label).map_or(label, String::as_str)
+    }
 
-                let side1 = (side + (4 - tile_one_rot)) % 4;
-                let side2 = (side + 2 + (4 - tile_two_rot)) % 4;
+    /// Whether `sockets` stay the same in the orientations `symmetry` treats as the same, like
+    /// a bitmap with that symmetry would
+    pub fn has_symmetry(&self, sockets: &TileSockets, symmetry: Symmetry) -> bool {
+        let orientations: Vec<TileSockets> = symmetry
+            .transforms()
+            .into_iter()
+            .map(|(rotation, reflected)| self.transform(sockets, rotation, reflected))
+            .collect();
+        [false, true].into_iter().all(|reflected| {
+            (0..4).all(|rotation| {
+                self.transform(sockets, rotation, reflected)
+                    == orientations[symmetry.orientation(rotation, reflected)]
+            })
+        })
+    }
 
-                if edges_match(tile1, side1, tile2, side2) {
-                    allowed_neighbors[tile_one_rot_idx][side][tile_two_rot_idx] = true;
-                }
-            }
+    /// Sockets of a tile rotated `rotation` times counterclockwise and then mirrored left to
+    /// right if `reflected`, matching [`BaseTile::transformed_bitmap`]
+    fn transform(&self, sockets: &TileSockets, rotation: u8, reflected: bool) -> TileSockets {
+        // a quarter turn counterclockwise moves the right edge to the top, the top edge to the
+        // left, and so on
+        let rotated: TileSockets =
+            std::array::from_fn(|side| sockets[(side + 3 * rotation as usize) % 4].clone());
+        if !reflected {
+            return rotated;
         }
+        // mirroring swaps left and right and reverses the reading direction of every edge
+        [0, 3, 2, 1].map(|side| self.mirror(&rotated[side]).to_string())
     }
+}
+
+/// Builds a tileset from (name, tile, weight) triples and the sockets of every tile, allowing
+/// two tiles next to each other wherever their touching sockets connect (see
+/// [`SocketMirrors`]). The bitmaps don't need to match
+pub fn socket_matched_tileset(
+    tile_size: usize,
+    materials: MaterialRegistry,
+    tile_defs: Vec<(String, BaseTile, f32)>,
+    sockets: Vec<TileSockets>,
+    mirrors: &SocketMirrors,
+) -> Tileset {
+    build_tileset(tile_size, materials, tile_defs, |_, variants| {
+        let variant_sockets: Vec<TileSockets> = variants
+            .iter()
+            .map(|v| mirrors.transform(&sockets[v.base_tile_idx], v.rotation, v.reflected))
+            .collect();
+        variant_sockets
+            .iter()
+            .map(|sockets1| {
+                [0, 1, 2, 3].map(|side| {
+                    let wanted = mirrors.mirror(&sockets1[side]);
+                    variant_sockets
+                        .iter()
+                        .map(|sockets2| sockets2[(side + 2) % 4] == wanted)
+                        .collect()
+                })
+            })
+            .collect()
+    })
+}
+
+/// Shared part of building tilesets from (name, tile, weight) triples. `allowed_neighbors`
+/// computes the adjacency from the base tiles and their variants
+fn build_tileset(
+    tile_size: usize,
+    materials: MaterialRegistry,
+    tile_defs: Vec<(String, BaseTile, f32)>,
+    allowed_neighbors: impl FnOnce(&[BaseTile], &[Tile]) -> Vec<[Vec<bool>; 4]>,
+) -> Tileset {
+    let tile_names: Vec<String> = tile_defs.iter().map(|(n, _, _)| n.clone()).collect();
+    let tiles: Vec<BaseTile> = tile_defs.iter().map(|(_, t, _)| t.clone()).collect();
+    let tile_weights: Vec<f32> = tile_defs.iter().map(|(_, _, w)| *w).collect();
+
+    let symmetries: Vec<Symmetry> = tiles.iter().map(|t| t.symmetry).collect();
+    let variants = tile_variants(&symmetries);
+    let allowed_neighbors = allowed_neighbors(&tiles, &variants);
+
+    let tile_map = HashMap::from_iter(tile_names.clone().into_iter().zip(tiles));
 
     Tileset {
         tiles: tile_map,
         tile_names,
+        variants,
         allowed_neighbors,
         tile_weights,
+        tile_size,
+        materials,
+    }
+}
+
+/// Default pins for the island race tileset: a single road end to grow the track from, or a
+/// straight piece of it if the road has to be a loop, which has no ends
+pub fn island_race_pins(width: usize, height: usize, roads: Option<RoadConstraint>) -> Vec<Pin> {
+    if width == 0 || height == 0 {
+        return Vec::new();
     }
+
+    let tile = match roads {
+        Some(RoadConstraint::Loop) => "road_straight",
+        _ => "road_end",
+    };
+    vec![Pin {
+        x: 5.min(width - 1),
+        y: 5.min(height - 1),
+        tile: tile.to_string(),
+        rotation: None,
+    }]
 }
 
 // make islands connected by singular path
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::procgen::wfc::{WaveFunctionCollapse, WfcConfig};
+
+    #[test]
+    fn sockets_from_edges_match_edge_matching() {
+        let edge_matched = make_island_race_tileset();
+        let mut tile_defs = Vec::new();
+        let mut sockets = Vec::new();
+        let mut pairs = BTreeSet::new();
+        for name in &edge_matched.tile_names {
+            let tile = edge_matched.tiles[name].clone();
+            let labels: TileSockets = [0, 1, 2, 3].map(|side| {
+                let edge = get_edge(&tile.bitmap, side);
+                let label: String = edge.iter().map(|bit| bit.0.to_string()).collect();
+                let reversed: String = label.chars().rev().collect();
+                if label != reversed {
+                    pairs.insert((
+                        label.clone().min(reversed.clone()),
+                        label.clone().max(reversed),
+                    ));
+                }
+                label
+            });
+            tile_defs.push((name.clone(), tile, 1.0));
+            sockets.push(labels);
+        }
+        let mirrors = SocketMirrors::new(&pairs.into_iter().collect::<Vec<_>>()).unwrap();
+
+        let socket_matched = socket_matched_tileset(
+            edge_matched.tile_size,
+            MaterialRegistry::default(),
+            tile_defs,
+            sockets,
+            &mirrors,
+        );
+        assert_eq!(socket_matched.variants, edge_matched.variants);
+        assert_eq!(
+            socket_matched.allowed_neighbors,
+            edge_matched.allowed_neighbors
+        );
+    }
+
+    #[test]
+    fn island_tiles_mirror_like_their_symmetry() {
+        let tileset = make_island_race_tileset();
+        // 2 straight roads, 4 turns, 4 road ends, space, grass, 4 edges, 4 corners, 4 inner
+        // corners
+        assert_eq!(tileset.variants.len(), 24);
+
+        for name in &tileset.tile_names {
+            let tile = &tileset.tiles[name];
+            let transforms = tile.symmetry.transforms();
+            for rotation in 0..4 {
+                let (r, f) = transforms[tile.symmetry.orientation(rotation, true)];
+                assert_eq!(
+                    tile.transformed_bitmap(rotation, true),
+                    tile.transformed_bitmap(r, f),
+                    "'{}' rotated {} times and mirrored",
+                    name,
+                    rotation
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn island_pins_fit_small_waves() {
+        assert!(island_race_pins(0, 4, None).is_empty());
+        assert!(island_race_pins(4, 0, None).is_empty());
+
+        let tileset = make_island_race_tileset();
+        for (width, height) in [(1, 1), (2, 9), (5, 5), (6, 6), (12, 3)] {
+            for roads in [None, Some(RoadConstraint::Loop)] {
+                let pins = island_race_pins(width, height, roads);
+                assert_eq!(pins.len(), 1);
+                let pin = &pins[0];
+                // (5, 5) unless the wave is too small for it
+                assert_eq!((pin.x, pin.y), (5.min(width - 1), 5.min(height - 1)));
+                let expected = match roads {
+                    Some(RoadConstraint::Loop) => "road_straight",
+                    _ => "road_end",
+                };
+                assert_eq!(pin.tile, expected);
+
+                let config = WfcConfig {
+                    pins,
+                    ..WfcConfig::default()
+                };
+                assert!(
+                    WaveFunctionCollapse::new(tileset.clone(), width, height, 0, config).is_ok(),
+                    "{}x{}",
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn edges_of_any_size_are_read_counterclockwise() {
+        // bit ids are the index in row-major order
+        let bitmap: TileBitmap = (0..3)
+            .map(|y| (0..3).map(|x| MaterialId(y * 3 + x)).collect())
+            .collect();
+        let edge = |side| -> Vec<u16> { get_edge(&bitmap, side).iter().map(|b| b.0).collect() };
+        assert_eq!(edge(0), [2, 1, 0]);
+        assert_eq!(edge(1), [0, 3, 6]);
+        assert_eq!(edge(2), [6, 7, 8]);
+        assert_eq!(edge(3), [8, 5, 2]);
+
+        // a tile's right edge touches the left edge of a copy of it only if the columns match
+        assert!(!edges_match(&bitmap, 3, &bitmap, 1));
+        let mut wrapping = bitmap.clone();
+        for row in &mut wrapping {
+            row[2] = row[0];
+        }
+        assert!(edges_match(&wrapping, 3, &wrapping, 1));
+    }
+}
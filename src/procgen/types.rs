@@ -1,46 +1,383 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use anyhow::Error;
+use image::DynamicImage;
+
+use crate::procgen::material::{MaterialId, MaterialRegistry};
 
 #[derive(Debug, Clone)]
 pub struct BaseTile {
     pub bitmap: TileBitmap,
+    pub symmetry: Symmetry,
+
+    /// image of the tile in its base orientation, if the tileset comes with images
+    pub img: Option<DynamicImage>,
+
+    /// one image per orientation for tilesets that draw every orientation separately
+    /// (`unique="True"` in tileset XML), indexed like [`Symmetry::rotate`]/[`Symmetry::reflect`].
+    /// Empty otherwise
+    pub orientation_imgs: Vec<DynamicImage>,
 }
 
-use serde::{Deserialize, Serialize};
+impl BaseTile {
+    /// Image of the tile rotated `rotation` times (counterclockwise, like the bitmap) and then
+    /// mirrored left to right if `reflected`, if the tileset comes with images
+    pub fn image(&self, rotation: u8, reflected: bool) -> Option<DynamicImage> {
+        if !self.orientation_imgs.is_empty() {
+            let orientation = self.symmetry.orientation(rotation, reflected);
+            return self.orientation_imgs.get(orientation).cloned();
+        }
+
+        let img = self.img.as_ref()?;
+        let rotated = match rotation % 4 {
+            0 => img.clone(),
+            1 => img.rotate270(),
+            2 => img.rotate180(),
+            _ => img.rotate90(),
+        };
+        Some(if reflected { rotated.fliph() } else { rotated })
+    }
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
-pub enum Bit {
-    Road,
-    Space,
-    Grass,
-    Dirt,
-    Empty,
+    /// Bitmap of the tile rotated `rotation` times counterclockwise and then mirrored left to
+    /// right if `reflected`
+    pub fn transformed_bitmap(&self, rotation: u8, reflected: bool) -> TileBitmap {
+        let base_bitmap = &self.bitmap;
+        let size = base_bitmap.len();
+        let mut bitmap = vec![vec![MaterialId::EMPTY; size]; size];
+        for (tile_y, row) in bitmap.iter_mut().enumerate() {
+            for (x, bit) in row.iter_mut().enumerate() {
+                let tile_x = if reflected { size - x - 1 } else { x };
+                *bit = match rotation % 4 {
+                    0 => base_bitmap[tile_y][tile_x],
+                    1 => base_bitmap[tile_x][size - tile_y - 1],
+                    2 => base_bitmap[size - tile_y - 1][size - tile_x - 1],
+                    _ => base_bitmap[size - tile_x - 1][tile_y],
+                };
+            }
+        }
+        bitmap
+    }
 }
 
-impl Bit {
-    pub fn color(&self) -> [f32; 4] {
+/// Which orientations of a tile look the same, named after a letter with the same symmetry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Symmetry {
+    /// same in every orientation
+    #[default]
+    X,
+
+    /// straight line: 2 orientations
+    I,
+
+    /// corner: 4 orientations, each a mirror image of another
+    L,
+
+    /// 4 orientations, mirror symmetric around one axis
+    T,
+
+    /// diagonal: 2 orientations, each a mirror image of the other
+    Backslash,
+
+    /// no symmetry: 4 rotations and their 4 mirror images
+    F,
+}
+
+impl Symmetry {
+    /// number of distinct orientations
+    pub fn cardinality(self) -> usize {
         match self {
-            Bit::Road => [0.3, 0.3, 0.3, 1.0],    // dark gray
-            Bit::Space => [0.9, 0.9, 0.95, 1.0],  // near white/light blue
-            Bit::Grass => [0.2, 0.7, 0.2, 1.0],   // green
-            Bit::Dirt => [0.55, 0.27, 0.07, 1.0], // brown
-            Bit::Empty => [0.0, 0.0, 0.0, 1.0],
+            Self::X => 1,
+            Self::I | Self::Backslash => 2,
+            Self::L | Self::T => 4,
+            Self::F => 8,
         }
     }
+
+    /// orientation after rotating `orientation` by 90 degrees (counterclockwise, like
+    /// [`Tile::rotation`])
+    pub fn rotate(self, orientation: usize) -> usize {
+        match self {
+            Self::X => orientation,
+            Self::I | Self::Backslash => 1 - orientation,
+            Self::L | Self::T => (orientation + 1) % 4,
+            Self::F if orientation < 4 => (orientation + 1) % 4,
+            Self::F => 4 + (orientation + 3) % 4,
+        }
+    }
+
+    /// orientation after mirroring `orientation` left to right
+    pub fn reflect(self, orientation: usize) -> usize {
+        match self {
+            Self::X | Self::I => orientation,
+            Self::Backslash => 1 - orientation,
+            Self::L if orientation.is_multiple_of(2) => orientation + 1,
+            Self::L => orientation - 1,
+            Self::T if orientation.is_multiple_of(2) => orientation,
+            Self::T => 4 - orientation,
+            Self::F if orientation < 4 => orientation + 4,
+            Self::F => orientation - 4,
+        }
+    }
+
+    /// orientation reached by rotating the base orientation `rotation` times and then mirroring
+    /// it if `reflected`
+    pub fn orientation(self, rotation: u8, reflected: bool) -> usize {
+        let rotated = (0..rotation).fold(0, |o, _| self.rotate(o));
+        if reflected {
+            self.reflect(rotated)
+        } else {
+            rotated
+        }
+    }
+
+    /// The simplest (rotation, reflected) that reaches each distinct orientation, in orientation
+    /// order. Reflections only show up for [`Symmetry::F`]
+    pub fn transforms(self) -> Vec<(u8, bool)> {
+        let candidates = [false, true]
+            .into_iter()
+            .flat_map(|reflected| (0..4).map(move |rotation| (rotation, reflected)));
+        let mut transforms: Vec<Option<(u8, bool)>> = vec![None; self.cardinality()];
+        for (rotation, reflected) in candidates {
+            let slot = &mut transforms[self.orientation(rotation, reflected)];
+            if slot.is_none() {
+                *slot = Some((rotation, reflected));
+            }
+        }
+        transforms.into_iter().flatten().collect()
+    }
 }
 
+impl FromStr for Symmetry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "X" => Ok(Self::X),
+            "I" => Ok(Self::I),
+            "L" => Ok(Self::L),
+            "T" => Ok(Self::T),
+            "\\" => Ok(Self::Backslash),
+            "F" => Ok(Self::F),
+            _ => Err(anyhow::anyhow!(
+                "unknown symmetry '{}' (expected X, I, L, T, \\ or F)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Symmetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let letter = match self {
+            Self::X => "X",
+            Self::I => "I",
+            Self::L => "L",
+            Self::T => "T",
+            Self::Backslash => "\\",
+            Self::F => "F",
+        };
+        f.write_str(letter)
+    }
+}
+
+/// A distinct orientation of a base tile. These are the options in the wave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Tile {
     pub base_tile_idx: usize,
+
+    /// number of counterclockwise quarter turns
     pub rotation: u8,
+
+    /// mirrored left to right after rotating
+    pub reflected: bool,
 }
 
-pub const TILE_SIZE: usize = 4;
+/// Every distinct orientation of every tile, in tile order
+pub fn tile_variants(symmetries: &[Symmetry]) -> Vec<Tile> {
+    symmetries
+        .iter()
+        .enumerate()
+        .flat_map(|(base_tile_idx, symmetry)| {
+            symmetry
+                .transforms()
+                .into_iter()
+                .map(move |(rotation, reflected)| Tile {
+                    base_tile_idx,
+                    rotation,
+                    reflected,
+                })
+        })
+        .collect()
+}
 
-pub type TileBitmap = [[Bit; TILE_SIZE]; TILE_SIZE];
+/// Square grid of bits, indexed `[y][x]`. Every tile in a tileset has the same size
+pub type TileBitmap = Vec<Vec<MaterialId>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Tileset {
     pub tiles: HashMap<String, BaseTile>,
     pub tile_names: Vec<String>,
+
+    /// distinct orientations of the tiles (see [`tile_variants`]). Indices into this are the
+    /// options in the wave and in `allowed_neighbors`
+    pub variants: Vec<Tile>,
+
+    /// `allowed_neighbors[variant][side][other]`: `other` may sit on `side` of `variant`
     pub allowed_neighbors: Vec<[Vec<bool>; 4]>,
+
+    /// weight of each orientation of a tile, indexed like `tile_names`
     pub tile_weights: Vec<f32>,
+
+    /// width (and height) of every tile bitmap, in bits
+    pub tile_size: usize,
+
+    /// materials the tile bitmaps refer to
+    pub materials: MaterialRegistry,
+}
+
+impl Tileset {
+    /// Base tile of a variant
+    pub fn base_tile(&self, variant: usize) -> &BaseTile {
+        let name = &self.tile_names[self.variants[variant].base_tile_idx];
+        &self.tiles[name]
+    }
+
+    /// weight of a variant
+    pub fn weight(&self, variant: usize) -> f32 {
+        self.tile_weights[self.variants[variant].base_tile_idx]
+    }
+
+    /// Hash of everything about the tileset that affects generation: the tiles, their bitmaps,
+    /// materials, weights and adjacency. Stable across builds, unlike `std::hash`, so it can be
+    /// saved
+    pub fn identity_hash(&self) -> u64 {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+
+        write(&(self.tile_size as u64).to_le_bytes());
+        for (name, weight) in self.tile_names.iter().zip(&self.tile_weights) {
+            write(name.as_bytes());
+            write(&[0]);
+            write(&weight.to_bits().to_le_bytes());
+            for bit in self.tiles[name].bitmap.iter().flatten() {
+                write(&bit.0.to_le_bytes());
+            }
+        }
+        for id in (0..self.materials.len() as u16).map(MaterialId) {
+            let material = self.materials.get(id);
+            write(material.name.as_bytes());
+            write(&[0]);
+            for channel in material.color {
+                write(&channel.to_bits().to_le_bytes());
+            }
+            write(&[
+                material.solid as u8,
+                material.island as u8,
+                material.flat as u8,
+                material.road as u8,
+            ]);
+        }
+        for (variant, sides) in self.variants.iter().zip(&self.allowed_neighbors) {
+            write(&(variant.base_tile_idx as u64).to_le_bytes());
+            write(&[variant.rotation, variant.reflected as u8]);
+            for allowed in sides.iter().flatten() {
+                write(&[*allowed as u8]);
+            }
+        }
+        hash
+    }
+
+    /// Width (and height) of the tile images, if every tile has one
+    pub fn image_size(&self) -> Option<u32> {
+        let mut sizes = self
+            .tiles
+            .values()
+            .map(|tile| tile.img.as_ref().map(|img| img.width()));
+        let first = sizes.next()??;
+        sizes.all(|size| size == Some(first)).then_some(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn images_turn_like_bitmaps() {
+        // every pixel and bit holds its own index, so the transforms can be compared directly
+        let size = 3;
+        let img: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(size, size, |x, y| Rgb([(y * size + x) as u8, 0, 0]));
+        let tile = BaseTile {
+            bitmap: (0..size)
+                .map(|y| {
+                    (0..size)
+                        .map(|x| MaterialId((y * size + x) as u16))
+                        .collect()
+                })
+                .collect(),
+            symmetry: Symmetry::F,
+            img: Some(DynamicImage::from(img)),
+            orientation_imgs: Vec::new(),
+        };
+
+        for (rotation, reflected) in Symmetry::F.transforms() {
+            let img = tile.image(rotation, reflected).unwrap().to_rgb8();
+            let bitmap = tile.transformed_bitmap(rotation, reflected);
+            for (y, row) in bitmap.iter().enumerate() {
+                for (x, bit) in row.iter().enumerate() {
+                    assert_eq!(
+                        img.get_pixel(x as u32, y as u32).0[0] as u16,
+                        bit.0,
+                        "rotation {}, reflected {}",
+                        rotation,
+                        reflected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unique_tiles_use_their_orientation_images() {
+        let solid =
+            |value| DynamicImage::from(ImageBuffer::from_pixel(2, 2, Rgb::<u8>([value, 0, 0])));
+        let tile = BaseTile {
+            bitmap: vec![vec![MaterialId::GRASS; 2]; 2],
+            symmetry: Symmetry::T,
+            img: Some(solid(0)),
+            orientation_imgs: (0..4).map(solid).collect(),
+        };
+        for rotation in 0..4 {
+            for reflected in [false, true] {
+                let orientation = Symmetry::T.orientation(rotation, reflected);
+                let img = tile.image(rotation, reflected).unwrap().to_rgb8();
+                assert_eq!(img.get_pixel(0, 0).0[0] as usize, orientation);
+            }
+        }
+    }
+
+    #[test]
+    fn variant_counts_match_symmetry() {
+        let expected = [
+            (Symmetry::X, 1),
+            (Symmetry::I, 2),
+            (Symmetry::L, 4),
+            (Symmetry::T, 4),
+            (Symmetry::Backslash, 2),
+            (Symmetry::F, 8),
+        ];
+        for (symmetry, count) in expected {
+            assert_eq!(tile_variants(&[symmetry]).len(), count, "{:?}", symmetry);
+            assert_eq!(symmetry.cardinality(), count, "{:?}", symmetry);
+        }
+    }
 }
@@ -1,25 +1,48 @@
-use std::collections::{HashSet, VecDeque};
-
-use anyhow::Error;
-use image::{DynamicImage, ImageBuffer, Rgb};
-use indicatif::{ProgressBar, ProgressStyle};
-use rand::{prelude::*, rngs::StdRng};
-
-use crate::procgen::types::{Bit, TILE_SIZE, Tile, Tileset};
-
-#[derive(Debug)]
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Error};
+use image::{DynamicImage, GenericImage, ImageBuffer, Rgb};
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+
+use crate::procgen::{
+    bitset::BitSet,
+    candidates::{Candidates, entropy_priority},
+    counts::{CountConstraints, CountLimits},
+    guide::{Guide, GuideMask},
+    material::{MaterialId, MaterialRegistry},
+    observer::WfcObserver,
+    roads::{RoadConstraint, RoadNetwork},
+    support::SupportCounts,
+    types::{Tile, TileBitmap, Tileset},
+};
+
+/// Size in pixels of a single bit in [`WaveFunctionCollapse::frame`] for tilesets without images
+const BIT_SCALE: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum WaveTile {
     Observed(usize),
-    Unobserved(Vec<bool>),
+    Unobserved(BitSet),
 }
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Bitmap {
-    pub bits: Vec<Bit>,
+    pub bits: Vec<MaterialId>,
     pub width: usize,
     pub height: usize,
+
+    /// what the bits mean. Worlds saved before materials were configurable only use the
+    /// built-in ones
+    #[serde(default)]
+    pub materials: MaterialRegistry,
 }
 
 impl Bitmap {
@@ -29,8 +52,7 @@ impl Bitmap {
 
         for y in 0..self.height {
             for x in 0..self.width {
-                let bit = &self.bits[y * self.width + x];
-                let [r, g, b, _] = bit.color();
+                let [r, g, b, _] = self.materials.get(self.bits[y * self.width + x]).color;
                 let pixel = Rgb([(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]);
                 img.put_pixel(x as u32, y as u32, pixel);
             }
@@ -45,7 +67,7 @@ impl Bitmap {
         let mut rng = StdRng::seed_from_u64(seed);
 
         // Helper to check if a bit is part of the island (not empty)
-        let is_island = |bit: &Bit| *bit != Bit::Space;
+        let is_island = |bit: &MaterialId| self.materials.get(*bit).island;
 
         // Find all edge pixels: island pixels adjacent to empty pixels
         let mut edges: Vec<(usize, usize)> = Vec::new();
@@ -119,8 +141,8 @@ impl Bitmap {
                 bottoms[nidx] = new_bottom;
 
                 // Top: weighted choice between -1, 0, +1, lower bounded by 1
-                // Force top=1 for roads
-                let new_top = if self.bits[nidx] == Bit::Road {
+                // Force top=1 for flat materials like roads
+                let new_top = if self.materials.get(self.bits[nidx]).flat {
                     1
                 } else {
                     let top_step = {
@@ -141,7 +163,12 @@ impl Bitmap {
             }
         }
 
-        HeightMap { bottoms, tops }
+        HeightMap {
+            bottoms,
+            tops,
+            width: self.width,
+            height: self.height,
+        }
     }
 }
 
@@ -149,417 +176,1923 @@ impl Bitmap {
 pub struct HeightMap {
     pub bottoms: Vec<i32>,
     pub tops: Vec<i32>,
+
+    /// dimensions of the bitmap the height map was computed for. 0 in worlds saved before these
+    /// were recorded
+    #[serde(default)]
+    pub width: usize,
+    #[serde(default)]
+    pub height: usize,
 }
 
 impl WaveTile {
-    fn num_possible_options(&self) -> usize {
-        match self {
-            Self::Observed(_) => usize::MAX,
-            Self::Unobserved(possibilities) => {
-                possibilities.iter().map(|b| if *b { 1 } else { 0 }).sum()
-            }
-        }
-    }
-
     #[inline]
     fn possible_options(&self) -> Vec<usize> {
         match self {
             Self::Observed(i) => vec![*i],
-            Self::Unobserved(possibilities) => possibilities
-                .iter()
-                .enumerate()
-                .filter_map(|(i, b)| if *b { Some(i) } else { None })
-                .collect(),
+            Self::Unobserved(possibilities) => possibilities.iter().collect(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Wave {
     pub tiles: Vec<WaveTile>,
     pub width: usize,
     pub height: usize,
+
+    /// whether opposite edges are neighbors, making the output tile seamlessly
+    pub periodic: bool,
 }
 
 impl Wave {
-    fn get(&self, x: usize, y: usize) -> &WaveTile {
+    pub fn get(&self, x: usize, y: usize) -> &WaveTile {
         self.tiles
             .get(y * self.width + x)
             .expect("out of bounds access")
     }
 
-    fn get_mut(&mut self, x: usize, y: usize) -> &mut WaveTile {
-        self.tiles
-            .get_mut(y * self.width + x)
-            .expect("out of bounds access")
+    /// Position of the neighbor on `side` (0: top, 1: left, 2: bottom, 3: right), if any
+    #[inline]
+    pub fn neighbor(&self, x: usize, y: usize, side: u8) -> Option<(usize, usize)> {
+        if self.periodic {
+            return Some(match side {
+                0 => (x, (y + self.height - 1) % self.height),
+                1 => ((x + self.width - 1) % self.width, y),
+                2 => (x, (y + 1) % self.height),
+                _ => ((x + 1) % self.width, y),
+            });
+        }
+
+        match side {
+            0 if y > 0 => Some((x, y - 1)),
+            1 if x > 0 => Some((x - 1, y)),
+            2 if y < self.height - 1 => Some((x, y + 1)),
+            3 if x < self.width - 1 => Some((x + 1, y)),
+            _ => None,
+        }
+    }
+}
+
+/// A wave tile ran out of options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contradiction {
+    pub x: usize,
+    pub y: usize,
+
+    /// neighbor whose propagation removed the last options, `None` if the options were removed
+    /// directly (e.g. by banning an observation while backtracking)
+    pub neighbor: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Contradiction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "contradiction: tile ({}, {}) has no options left",
+            self.x, self.y
+        )?;
+        if let Some((nx, ny)) = self.neighbor {
+            write!(f, " after propagating from neighbor ({}, {})", nx, ny)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Contradiction {}
+
+/// Limits for recovering from contradictions by undoing observations
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BacktrackConfig {
+    /// max number of observations that can be undone (older decisions and their trails are dropped)
+    pub max_depth: usize,
+
+    /// max number of undos before giving up on the run
+    pub max_retries: usize,
+}
+
+impl Default for BacktrackConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_retries: 1000,
+        }
+    }
+}
+
+/// How removed options are propagated through the wave. Both reach the same result, so the
+/// same seed produces the same output with either
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Propagator {
+    /// re-check every neighbor of a changed tile against the union of its compatibility masks
+    #[default]
+    Masks,
+
+    /// keep per-option support counts (AC-4) and only propagate removals
+    SupportCount,
+}
+
+impl FromStr for Propagator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "masks" => Ok(Self::Masks),
+            "support-count" => Ok(Self::SupportCount),
+            _ => Err(anyhow::anyhow!(
+                "unknown propagator '{}' (expected masks or support-count)",
+                s
+            )),
+        }
+    }
+}
+
+/// How the next tile to observe is picked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelectionHeuristic {
+    /// lowest weighted Shannon entropy, with a little noise to break ties
+    Entropy,
+
+    /// fewest options left (minimum remaining values), ties broken randomly
+    #[default]
+    Mrv,
+
+    /// first unobserved tile in row-major order
+    Scanline,
+}
+
+impl FromStr for SelectionHeuristic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "entropy" => Ok(Self::Entropy),
+            "mrv" => Ok(Self::Mrv),
+            "scanline" => Ok(Self::Scanline),
+            _ => Err(anyhow::anyhow!(
+                "unknown heuristic '{}' (expected entropy, mrv or scanline)",
+                s
+            )),
+        }
+    }
+}
+
+/// Rectangle of wave tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x.checked_sub(self.x).is_some_and(|dx| dx < self.width)
+            && y.checked_sub(self.y).is_some_and(|dy| dy < self.height)
+    }
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    /// Parses `x,y,width,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [x, y, width, height] = parts.as_slice() else {
+            return Err(anyhow::anyhow!("expected x,y,width,height, got '{}'", s));
+        };
+
+        Ok(Region {
+            x: x.parse()?,
+            y: y.parse()?,
+            width: width.parse()?,
+            height: height.parse()?,
+        })
     }
 }
 
+/// A tile fixed in place before generation starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub x: usize,
+    pub y: usize,
+    pub tile: String,
+
+    /// `None` picks a random rotation that is still possible
+    pub rotation: Option<u8>,
+}
+
+impl FromStr for Pin {
+    type Err = Error;
+
+    /// Parses `x,y,tile` or `x,y,tile,rotation`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (x, y, tile, rotation) = match parts.as_slice() {
+            [x, y, tile] => (x, y, tile, None),
+            [x, y, tile, rotation] => (x, y, tile, Some(rotation.parse()?)),
+            _ => return Err(anyhow::anyhow!("expected x,y,tile[,rotation], got '{}'", s)),
+        };
+
+        Ok(Pin {
+            x: x.parse()?,
+            y: y.parse()?,
+            tile: tile.to_string(),
+            rotation,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WfcConfig {
+    /// backtrack on contradictions instead of stopping (`None` stops at the first one)
+    pub backtracking: Option<BacktrackConfig>,
+
+    pub propagator: Propagator,
+
+    pub heuristic: SelectionHeuristic,
+
+    /// tiles observed before the first step
+    pub pins: Vec<Pin>,
+
+    /// wrap around the edges so the output tiles seamlessly
+    pub periodic: bool,
+
+    /// keep the road bits in one network (`None` lets roads end up anywhere)
+    pub roads: Option<RoadConstraint>,
+
+    /// bounds on how often tiles and road show up in the whole output
+    pub counts: CountConstraints,
+
+    /// painted image restricting and reweighting the options of the tiles it covers
+    pub guide: Option<Guide>,
+}
+
+/// A change made to the wave, recorded so it can be undone
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum Change {
+    /// `option` was taken from unobserved tile `idx`
+    Removed { idx: usize, option: usize },
+
+    /// tile `idx` was observed as `option`, its only option left at that point
+    Observed { idx: usize, option: usize },
+}
+
+/// An observation the solver chose, and the trail of every change made to the wave since, so
+/// the observation can be undone by replaying the trail backwards
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Decision {
+    x: usize,
+    y: usize,
+    observation: usize,
+    trail: Vec<Change>,
+}
+
+/// Adds `change` to the trail of the latest decision. Changes made before any decision (or
+/// without backtracking) are never undone, so they aren't kept
+fn record(history: &mut VecDeque<Decision>, change: Change) {
+    if let Some(decision) = history.back_mut() {
+        decision.trail.push(change);
+    }
+}
+
+/// Everything a [`WaveFunctionCollapse`] needs to pick up where it left off, so a run can be
+/// saved to disk and continued later with exactly the same result as running it through. The
+/// tileset isn't included, only a hash to check that the same one is used to continue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolverState {
+    /// [`Tileset::identity_hash`] of the tileset the run started with
+    pub tileset_hash: u64,
+
+    /// seed the run started with
+    pub seed: u64,
+    pub steps: usize,
+    pub retries: usize,
+
+    wave: Wave,
+    rng: ChaCha12Rng,
+    tiebreaks: Vec<u32>,
+    history: VecDeque<Decision>,
+    backtracking: Option<BacktrackConfig>,
+    propagator: Propagator,
+    heuristic: SelectionHeuristic,
+    roads: Option<RoadNetwork>,
+    counts: Option<CountLimits>,
+    guide: Option<GuideMask>,
+}
+
+impl SolverState {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let json = serde_json::to_string(self)?;
+        fs::write(path, json)
+            .with_context(|| format!("Failed to write solver state: {}", path.display()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read solver state: {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("in {}", path.display()))
+    }
+}
+
+/// `masks[option][side]` holds every option allowed on `side` of `option`
+fn compatibility_masks(tileset: &Tileset) -> Vec<[BitSet; 4]> {
+    let num_options = tileset.allowed_neighbors.len();
+    tileset
+        .allowed_neighbors
+        .iter()
+        .map(|sides| {
+            sides.each_ref().map(|allowed| {
+                let mut mask = BitSet::empty(num_options);
+                for (option, _) in allowed.iter().enumerate().filter(|(_, a)| **a) {
+                    mask.insert(option);
+                }
+                mask
+            })
+        })
+        .collect()
+}
+
 pub struct WaveFunctionCollapse {
     tileset: Tileset,
+    masks: Vec<[BitSet; 4]>,
+
+    /// only kept with [`Propagator::SupportCount`]
+    supports: Option<SupportCounts>,
+
+    /// only kept with a road constraint
+    roads: Option<RoadNetwork>,
+
+    /// only kept with count constraints
+    counts: Option<CountLimits>,
+
+    /// only kept with a guide
+    guide: Option<GuideMask>,
     pub wave: Wave,
-    rng: StdRng,
+    seed: u64,
+    rng: ChaCha12Rng,
+    config: WfcConfig,
+    history: VecDeque<Decision>,
+    retries: usize,
+    steps: usize,
+
+    /// number of tiles not observed yet
+    unobserved: usize,
+
+    /// unobserved tiles in the order the heuristic observes them
+    candidates: Candidates,
+
+    /// fixed random tiebreak per tile, so the order doesn't depend on how propagation ran
+    tiebreaks: Vec<u32>,
 }
 
 impl WaveFunctionCollapse {
-    pub fn new(tileset: Tileset, width: usize, height: usize, seed: u64) -> Self {
+    pub fn new(
+        tileset: Tileset,
+        width: usize,
+        height: usize,
+        seed: u64,
+        config: WfcConfig,
+    ) -> Result<Self, Error> {
+        if width == 0 || height == 0 {
+            return Err(anyhow::anyhow!("{}x{} wave is empty", width, height));
+        }
+
         // populate WaveSlots in Unobserved state
-        let mut superposition = Vec::new();
-        for _ in 0..(tileset.tile_names.len() * 4) {
-            superposition.push(true);
+        let superposition = BitSet::full(tileset.variants.len());
+        let tiles = vec![WaveTile::Unobserved(superposition); width * height];
+        Self::from_tiles(tileset, tiles, width, height, seed, config)
+    }
+
+    /// Picks up a finished `bitmap`, e.g. from a saved world, and clears the wave tiles in
+    /// `region` so only they are generated again. Every other tile keeps its bits, and
+    /// constraints propagate into the region from them. Errors if the bitmap doesn't consist of
+    /// tiles from the tileset
+    pub fn regenerate(
+        tileset: Tileset,
+        bitmap: &Bitmap,
+        region: Region,
+        seed: u64,
+        config: WfcConfig,
+    ) -> Result<Self, Error> {
+        let tile_size = tileset.tile_size;
+        if !bitmap.width.is_multiple_of(tile_size) || !bitmap.height.is_multiple_of(tile_size) {
+            return Err(anyhow::anyhow!(
+                "{}x{} bitmap can't be split into {}x{} tiles",
+                bitmap.width,
+                bitmap.height,
+                tile_size,
+                tile_size
+            ));
+        }
+        let (width, height) = (bitmap.width / tile_size, bitmap.height / tile_size);
+        let fits = |start: usize, size: usize, limit: usize| {
+            start.checked_add(size).is_some_and(|end| end <= limit)
+        };
+        if !fits(region.x, region.width, width) || !fits(region.y, region.height, height) {
+            return Err(anyhow::anyhow!(
+                "region {},{},{},{} is outside the {}x{} wave",
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                width,
+                height
+            ));
         }
 
-        let mut tiles = Vec::new();
-        for _ in 0..(width * height) {
-            tiles.push(WaveTile::Unobserved(superposition.clone()));
+        let num_options = tileset.variants.len();
+        let variant_bitmaps: Vec<TileBitmap> = tileset
+            .variants
+            .iter()
+            .enumerate()
+            .map(|(option, v)| {
+                tileset
+                    .base_tile(option)
+                    .transformed_bitmap(v.rotation, v.reflected)
+            })
+            .collect();
+
+        let mut tiles = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                if region.contains(x, y) {
+                    tiles.push(WaveTile::Unobserved(BitSet::full(num_options)));
+                    continue;
+                }
+
+                // several variants can look the same, so keep all of them and let propagation
+                // sort it out
+                let mut matches = BitSet::empty(num_options);
+                for (option, variant_bitmap) in variant_bitmaps.iter().enumerate() {
+                    let same = variant_bitmap.iter().enumerate().all(|(tile_y, row)| {
+                        let start = (y * tile_size + tile_y) * bitmap.width + x * tile_size;
+                        bitmap.bits[start..start + tile_size] == row[..]
+                    });
+                    if same {
+                        matches.insert(option);
+                    }
+                }
+                match matches.count() {
+                    0 => {
+                        return Err(anyhow::anyhow!(
+                            "bits of wave tile ({}, {}) don't match any tile in the tileset",
+                            x,
+                            y
+                        ));
+                    }
+                    1 => tiles.push(WaveTile::Observed(matches.iter().next().unwrap())),
+                    _ => tiles.push(WaveTile::Unobserved(matches)),
+                }
+            }
         }
 
-        let rng = rand::rngs::StdRng::seed_from_u64(seed);
+        Self::from_tiles(tileset, tiles, width, height, seed, config)
+    }
+
+    /// Sets up the wave from the initial state of every tile, then applies the constraints and
+    /// pins of `config`
+    fn from_tiles(
+        tileset: Tileset,
+        tiles: Vec<WaveTile>,
+        width: usize,
+        height: usize,
+        seed: u64,
+        config: WfcConfig,
+    ) -> Result<Self, Error> {
+        let masks = compatibility_masks(&tileset);
+        let mut wave = Wave {
+            tiles,
+            width,
+            height,
+            periodic: config.periodic,
+        };
+        let roads = config
+            .roads
+            .map(|constraint| RoadNetwork::new(&tileset, constraint))
+            .transpose()?;
+        if let Some(roads) = &roads {
+            roads.restrict(&mut wave)?;
+        }
+        let guide = match &config.guide {
+            Some(guide) => Some(GuideMask::new(guide, &tileset, width, height)?),
+            None => None,
+        };
+        if let Some(guide) = &guide {
+            guide.restrict(&mut wave)?;
+        }
+        let counts = match config.counts.is_empty() {
+            true => None,
+            false => Some(CountLimits::new(&tileset, &config.counts, width * height)?),
+        };
+        let supports = match config.propagator {
+            Propagator::Masks => None,
+            Propagator::SupportCount => Some(SupportCounts::new(&masks, &wave)),
+        };
+
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let tiebreaks = (0..(width * height)).map(|_| rng.random()).collect();
 
         let mut wfc = WaveFunctionCollapse {
             tileset,
-            wave: Wave {
-                tiles,
-                width,
-                height,
-            },
+            masks,
+            supports,
+            roads,
+            counts,
+            guide,
+            wave,
+            seed,
             rng,
+            config,
+            history: VecDeque::new(),
+            retries: 0,
+            steps: 0,
+            unobserved: 0,
+            candidates: Candidates::new(width * height),
+            tiebreaks,
         };
+        wfc.rebuild_candidates();
+        wfc.propagate_everywhere()?;
+
+        for pin in wfc.config.pins.clone() {
+            wfc.collapse_xy_to_tile(pin.x, pin.y, &pin.tile, pin.rotation)?;
+        }
+
+        Ok(wfc)
+    }
+
+    /// Saves the state of the run so it can be continued with [`Self::restore`]
+    pub fn snapshot(&self) -> SolverState {
+        SolverState {
+            tileset_hash: self.tileset.identity_hash(),
+            seed: self.seed,
+            steps: self.steps,
+            retries: self.retries,
+            wave: self.wave.clone(),
+            rng: self.rng.clone(),
+            tiebreaks: self.tiebreaks.clone(),
+            history: self.history.clone(),
+            backtracking: self.config.backtracking,
+            propagator: self.config.propagator,
+            heuristic: self.config.heuristic,
+            roads: self.roads.clone(),
+            counts: self.counts.clone(),
+            guide: self.guide.clone(),
+        }
+    }
 
-        // Collapse a random tile into a path end to seed the generation
-        // wfc.collapse_random_to_tile("road_end");
-        wfc.collapse_xy_to_tile(5, 5, "road_end");
+    /// Continues a run saved with [`Self::snapshot`]. Errors if `tileset` isn't the one the run
+    /// started with
+    pub fn restore(tileset: Tileset, state: SolverState) -> Result<Self, Error> {
+        let hash = tileset.identity_hash();
+        if hash != state.tileset_hash {
+            return Err(anyhow::anyhow!(
+                "solver state was saved with a different tileset (hash {:016x}, this one is {:016x})",
+                state.tileset_hash,
+                hash
+            ));
+        }
+        let num_tiles = state.wave.width * state.wave.height;
+        if state.wave.tiles.len() != num_tiles || state.tiebreaks.len() != num_tiles {
+            return Err(anyhow::anyhow!(
+                "solver state has {} wave tiles and {} tiebreaks, expected {}",
+                state.wave.tiles.len(),
+                state.tiebreaks.len(),
+                num_tiles
+            ));
+        }
 
-        wfc
+        let masks = compatibility_masks(&tileset);
+        let supports = match state.propagator {
+            Propagator::Masks => None,
+            Propagator::SupportCount => Some(SupportCounts::new(&masks, &state.wave)),
+        };
+        // pins, constraints and the guide were applied when the run started. Only the settings
+        // that still matter while stepping are kept
+        let config = WfcConfig {
+            backtracking: state.backtracking,
+            propagator: state.propagator,
+            heuristic: state.heuristic,
+            periodic: state.wave.periodic,
+            roads: state.roads.as_ref().map(|roads| roads.constraint()),
+            ..WfcConfig::default()
+        };
+
+        let mut wfc = WaveFunctionCollapse {
+            tileset,
+            masks,
+            supports,
+            roads: state.roads,
+            counts: state.counts,
+            guide: state.guide,
+            wave: state.wave,
+            seed: state.seed,
+            rng: state.rng,
+            config,
+            history: state.history,
+            retries: state.retries,
+            steps: state.steps,
+            unobserved: 0,
+            candidates: Candidates::new(num_tiles),
+            tiebreaks: state.tiebreaks,
+        };
+        wfc.rebuild_candidates();
+        Ok(wfc)
     }
 
-    /// Collapse a specific tile position to a specific tile type (any rotation)
-    pub fn collapse_xy_to_tile(&mut self, x: usize, y: usize, tile_name: &str) {
+    /// Collapse a specific tile position to a specific tile type. With no rotation given, picks
+    /// a random one among those still possible there
+    pub fn collapse_xy_to_tile(
+        &mut self,
+        x: usize,
+        y: usize,
+        tile_name: &str,
+        rotation: Option<u8>,
+    ) -> Result<(), Error> {
+        if x >= self.wave.width || y >= self.wave.height {
+            return Err(anyhow::anyhow!(
+                "({}, {}) is outside the {}x{} wave",
+                x,
+                y,
+                self.wave.width,
+                self.wave.height
+            ));
+        }
+
         // Find the base tile index for this name
         let base_idx = self
             .tileset
             .tile_names
             .iter()
             .position(|n| n == tile_name)
-            .expect("tile name not found");
+            .ok_or(anyhow::anyhow!("tile '{}' not in tileset", tile_name))?;
 
-        // Pick a random rotation (0-3)
-        let rotation = self.rng.random_range(0..4);
-        let tile_idx = base_idx * 4 + rotation;
+        if let Some(rotation) = rotation
+            && rotation >= 4
+        {
+            return Err(anyhow::anyhow!("rotation must be 0-3, got {}", rotation));
+        }
+
+        // symmetric tiles look the same under several rotations, so match by orientation
+        let symmetry = self.tileset.tiles[tile_name].symmetry;
+        let orientation = rotation.map(|rotation| symmetry.orientation(rotation, false));
+        let possible: Vec<usize> = (0..self.tileset.variants.len())
+            .filter(|&option| {
+                let variant = &self.tileset.variants[option];
+                variant.base_tile_idx == base_idx
+                    && orientation.is_none_or(|orientation| {
+                        symmetry.orientation(variant.rotation, variant.reflected) == orientation
+                    })
+            })
+            .filter(|&option| match self.wave.get(x, y) {
+                WaveTile::Observed(observed) => *observed == option,
+                WaveTile::Unobserved(options) => options.contains(option),
+            })
+            .collect();
 
-        *self.wave.get_mut(x, y) = WaveTile::Observed(tile_idx);
-        self.propagate_from(x, y);
+        let Some(&tile_idx) = possible.choose(&mut self.rng) else {
+            return Err(Contradiction {
+                x,
+                y,
+                neighbor: None,
+            }
+            .into());
+        };
+
+        let removed = self.observe(x, y, tile_idx);
+        self.propagate(x, y, &removed, &mut ())?;
+        Ok(())
+    }
+
+    /// Marks a tile as observed. Returns the options that were removed from it
+    fn observe(&mut self, x: usize, y: usize, option: usize) -> BitSet {
+        let mut removed = BitSet::empty(self.masks.len());
+        let idx = y * self.wave.width + x;
+        let tile = &mut self.wave.tiles[idx];
+        if let WaveTile::Unobserved(options) = tile {
+            removed.union_with(options);
+            removed.remove(option);
+            self.unobserved -= 1;
+            for other in removed.iter() {
+                record(&mut self.history, Change::Removed { idx, option: other });
+            }
+            record(&mut self.history, Change::Observed { idx, option });
+        }
+        *tile = WaveTile::Observed(option);
+        self.constraints_changed(idx);
+        removed
+    }
+
+    /// Selection priority of an unobserved tile under the configured heuristic (lower goes first)
+    fn priority(&self, idx: usize) -> u64 {
+        let WaveTile::Unobserved(options) = &self.wave.tiles[idx] else {
+            return u64::MAX;
+        };
+
+        match self.config.heuristic {
+            SelectionHeuristic::Mrv => options.count() as u64,
+            SelectionHeuristic::Scanline => 0,
+            SelectionHeuristic::Entropy => {
+                let weights = options
+                    .iter()
+                    .map(|option| self.tileset.weight(option) as f64);
+                entropy_priority(weights, self.tiebreaks[idx])
+            }
+        }
+    }
+
+    fn push_candidate(&mut self, x: usize, y: usize) {
+        let idx = y * self.wave.width + x;
+        let tiebreak = match self.config.heuristic {
+            SelectionHeuristic::Scanline => 0,
+            _ => self.tiebreaks[idx],
+        };
+        self.candidates.push(idx, self.priority(idx), tiebreak);
+    }
+
+    /// Keeps the candidates and the global constraints up to date after the options of tile
+    /// `idx` changed
+    fn tile_changed(&mut self, idx: usize) {
+        self.push_candidate(idx % self.wave.width, idx / self.wave.width);
+        self.constraints_changed(idx);
+    }
+
+    /// Lets the global constraints know that tile `idx` changed, which they only look at again
+    /// once told
+    fn constraints_changed(&mut self, idx: usize) {
+        if let Some(roads) = &mut self.roads {
+            roads.update(&self.wave, idx);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.update(&self.wave, idx);
+        }
+    }
+
+    fn rebuild_candidates(&mut self) {
+        self.candidates.clear();
+        self.unobserved = 0;
+        for y in 0..self.wave.height {
+            for x in 0..self.wave.width {
+                if matches!(self.wave.get(x, y), WaveTile::Unobserved(_)) {
+                    self.unobserved += 1;
+                    self.push_candidate(x, y);
+                }
+            }
+        }
+    }
+
+    /// Pops the unobserved tile the heuristic wants to observe next
+    fn next_candidate(&mut self) -> Option<(usize, usize)> {
+        let idx = self.candidates.pop(&self.wave)?;
+        Some((idx % self.wave.width, idx / self.wave.width))
     }
 
     /// Collapse a random unobserved tile to a specific tile type (any rotation)
-    pub fn _collapse_random_to_tile(&mut self, tile_name: &str) {
+    pub fn _collapse_random_to_tile(&mut self, tile_name: &str) -> Result<(), Error> {
         // Find all unobserved tiles
         let unobserved: Vec<(usize, usize)> = (0..self.wave.height)
             .flat_map(|y| (0..self.wave.width).map(move |x| (x, y)))
             .filter(|&(x, y)| matches!(self.wave.get(x, y), WaveTile::Unobserved(_)))
             .collect();
 
-        if let Some(&(x, y)) = unobserved.choose(&mut self.rng) {
-            self.collapse_xy_to_tile(x, y, tile_name);
+        match unobserved.choose(&mut self.rng) {
+            Some(&(x, y)) => self.collapse_xy_to_tile(x, y, tile_name, None),
+            None => Ok(()),
         }
     }
 
-    /// Propagate constraints from a specific position
-    fn propagate_from(&mut self, start_x: usize, start_y: usize) {
-        let mut propagation_queue = VecDeque::new();
-        propagation_queue.push_back((start_x, start_y));
-        let mut visited = HashSet::new();
+    /// Propagate constraints from a tile whose `removed` options were just taken away
+    fn propagate(
+        &mut self,
+        x: usize,
+        y: usize,
+        removed: &BitSet,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        match self.config.propagator {
+            Propagator::Masks => self.propagate_masks(VecDeque::from([(x, y)]), observer)?,
+            Propagator::SupportCount => {
+                let idx = y * self.wave.width + x;
+                self.propagate_supports(Some((idx, removed)), Vec::new(), observer)?
+            }
+        }
+        self.enforce_global_constraints(x, y, observer)
+    }
 
-        while let Some((x, y)) = propagation_queue.pop_front() {
-            if visited.contains(&(x, y)) {
+    /// Removes options the road and count constraints rule out, until neither has anything
+    /// left to remove. `(x, y)` is the tile that last changed
+    fn enforce_global_constraints(
+        &mut self,
+        x: usize,
+        y: usize,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        loop {
+            let mut removals = match &mut self.roads {
+                Some(roads) => roads.prune(&self.wave, x, y)?,
+                None => Vec::new(),
+            };
+            if removals.is_empty()
+                && let Some(counts) = &mut self.counts
+            {
+                removals = counts.prune(&self.wave, x, y)?;
+            }
+            if removals.is_empty() {
+                return Ok(());
+            }
+            self.remove_options(removals, observer)?;
+        }
+    }
+
+    /// Removes each (tile index, option) from the wave and propagates the removals. Options that
+    /// are already gone are skipped
+    fn remove_options(
+        &mut self,
+        removals: Vec<(usize, usize)>,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        if self.config.propagator == Propagator::SupportCount {
+            return self.propagate_supports(None, removals, observer);
+        }
+
+        let width = self.wave.width;
+        let mut queue = VecDeque::new();
+        for (idx, option) in removals {
+            let (x, y) = (idx % width, idx / width);
+            let WaveTile::Unobserved(options) = &mut self.wave.tiles[idx] else {
                 continue;
-            } else {
-                visited.insert((x, y));
-            }
-
-            let center = self.wave.get(x, y);
-            let children = self.get_children(x, y);
-            let center_possible_options = center.possible_options();
-
-            for ((child_x, child_y), side) in children {
-                let child = self.wave.get(child_x, child_y);
-                let disallowed_options = match child {
-                    WaveTile::Unobserved(_) => {
-                        let child_possible = child.possible_options();
-                        let mut disallowed = Vec::new();
-
-                        'child_loop: for &child_opt in &child_possible {
-                            for &center_opt in center_possible_options.iter() {
-                                if self.is_allowed(center_opt, child_opt, side) {
-                                    continue 'child_loop;
-                                }
-                            }
-                            disallowed.push(child_opt);
-                        }
+            };
+            if !options.contains(option) {
+                continue;
+            }
 
-                        disallowed
-                    }
-                    WaveTile::Observed(_) => Vec::new(),
+            options.remove(option);
+            let emptied = options.is_empty();
+            record(&mut self.history, Change::Removed { idx, option });
+            observer.option_removed(x, y, option);
+            self.tile_changed(idx);
+            if emptied {
+                return Err(Contradiction {
+                    x,
+                    y,
+                    neighbor: None,
+                });
+            }
+            // removals of the same tile come one after another
+            if queue.back() != Some(&(x, y)) {
+                queue.push_back((x, y));
+            }
+        }
+        self.propagate_masks(queue, observer)
+    }
+
+    /// Propagate until every option in the wave is allowed by all of its neighbors. Only runs
+    /// while setting up the wave, before anyone observes it
+    fn propagate_everywhere(&mut self) -> Result<(), Contradiction> {
+        match self.config.propagator {
+            Propagator::Masks => {
+                let width = self.wave.width;
+                let queue = (0..self.wave.tiles.len())
+                    .map(|idx| (idx % width, idx / width))
+                    .collect();
+                self.propagate_masks(queue, &mut ())?
+            }
+            Propagator::SupportCount => {
+                let supports = self.supports.as_ref().expect("support counts are kept");
+                let removals = supports.unsupported(&self.wave);
+                self.propagate_supports(None, removals, &mut ())?
+            }
+        }
+        self.enforce_global_constraints(0, 0, &mut ())
+    }
+
+    /// Propagate removals of (tile index, option) through the support counts, after the
+    /// `observed` tile (if any) lost the given options to its observation
+    fn propagate_supports(
+        &mut self,
+        observed: Option<(usize, &BitSet)>,
+        removals: Vec<(usize, usize)>,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        let supports = self.supports.as_mut().expect("support counts are kept");
+        let width = self.wave.width;
+        let history = &mut self.history;
+        let mut changed = Vec::new();
+        let on_remove = |idx, option| {
+            record(history, Change::Removed { idx, option });
+            observer.option_removed(idx % width, idx / width, option);
+            // removals from the same tile come in runs
+            if changed.last() != Some(&idx) {
+                changed.push(idx);
+            }
+        };
+        let result = match observed {
+            Some((idx, removed)) => {
+                supports.propagate_observed(&self.masks, &mut self.wave, idx, removed, on_remove)
+            }
+            None => supports.propagate(&self.masks, &mut self.wave, removals, on_remove),
+        };
+
+        for idx in changed {
+            self.tile_changed(idx);
+        }
+        result
+    }
+
+    /// Propagate constraints from the queued positions until nothing changes
+    fn propagate_masks(
+        &mut self,
+        mut propagation_queue: VecDeque<(usize, usize)>,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        let mut allowed = BitSet::empty(self.masks.len());
+
+        while let Some((x, y)) = propagation_queue.pop_front() {
+            for side in 0..4 {
+                let Some((child_x, child_y)) = self.wave.neighbor(x, y, side) else {
+                    continue;
                 };
+                if matches!(self.wave.get(child_x, child_y), WaveTile::Observed(_)) {
+                    continue;
+                }
 
-                if let WaveTile::Unobserved(items) = self.wave.get_mut(child_x, child_y) {
-                    for option in disallowed_options {
-                        items[option] = false;
+                // A child option survives if ANY center option allows it on this side
+                allowed.clear();
+                match self.wave.get(x, y) {
+                    WaveTile::Observed(center_opt) => {
+                        allowed.union_with(&self.masks[*center_opt][side as usize])
                     }
+                    WaveTile::Unobserved(center_options) => {
+                        for center_opt in center_options.iter() {
+                            allowed.union_with(&self.masks[center_opt][side as usize]);
+                        }
+                    }
+                }
 
-                    if !visited.contains(&(child_x, child_y)) {
-                        propagation_queue.push_back((child_x, child_y));
+                let child_idx = child_y * self.wave.width + child_x;
+                if let WaveTile::Unobserved(items) = &mut self.wave.tiles[child_idx]
+                    && !items.is_subset(&allowed)
+                {
+                    for option in items.iter().filter(|option| !allowed.contains(*option)) {
+                        record(
+                            &mut self.history,
+                            Change::Removed {
+                                idx: child_idx,
+                                option,
+                            },
+                        );
+                        observer.option_removed(child_x, child_y, option);
                     }
+                    items.intersect_with(&allowed);
+                    let emptied = items.is_empty();
+                    self.tile_changed(child_idx);
+                    if emptied {
+                        return Err(Contradiction {
+                            x: child_x,
+                            y: child_y,
+                            neighbor: Some((x, y)),
+                        });
+                    }
+                    propagation_queue.push_back((child_x, child_y));
                 }
             }
         }
+
+        Ok(())
     }
 
-    pub fn step(&mut self) -> Result<(), Error> {
-        // find lowest entropy tile
-        let mut lowest_possibilities = self.tileset.tile_names.len() * 4;
-        let mut xy = vec![(0, 0)];
-        for y in 0..(self.wave.height) {
-            for x in 0..(self.wave.width) {
-                let possibilities = self.wave.get(x, y).num_possible_options();
-                if possibilities < lowest_possibilities {
-                    lowest_possibilities = possibilities;
-                    xy = vec![(x, y)];
-                } else if possibilities == lowest_possibilities {
-                    xy.push((x, y));
-                }
-            }
+    /// whether every tile in the wave has been observed
+    pub fn is_finished(&self) -> bool {
+        self.unobserved == 0
+    }
+
+    /// number of steps taken so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// number of tiles not observed yet
+    pub fn unobserved(&self) -> usize {
+        self.unobserved
+    }
+
+    /// Observes the next tile and propagates, reporting what happens to `observer`. Returns the
+    /// contradiction if the wave ran into one it couldn't backtrack out of
+    pub fn step(&mut self, observer: &mut dyn WfcObserver) -> Result<(), Contradiction> {
+        let was_finished = self.is_finished();
+        if let Err(contradiction) = self.observe_next(observer) {
+            observer.contradiction(self, contradiction);
+            return Err(contradiction);
+        }
+        if self.is_finished() && !was_finished {
+            observer.finished(self);
         }
+        Ok(())
+    }
 
-        let xy = *xy
-            .choose(&mut self.rng)
-            .ok_or(anyhow::anyhow!("All steps taken"))?;
+    fn observe_next(&mut self, observer: &mut dyn WfcObserver) -> Result<(), Contradiction> {
+        // find lowest entropy tile
+        let Some((x, y)) = self.next_candidate() else {
+            return Ok(());
+        };
+        self.steps += 1;
 
         // collapse lowest entropy tile with weighted choice
         let observation = {
-            let tile = self.wave.get(xy.0, xy.1);
+            let tile = self.wave.get(x, y);
             let possible_options = tile.possible_options();
+            let mut factors = match &mut self.counts {
+                Some(counts) => counts.weight_factors(&self.wave, &possible_options),
+                None => vec![1.0; possible_options.len()],
+            };
+            if let Some(guide) = &self.guide {
+                let idx = y * self.wave.width + x;
+                for (option, factor) in possible_options.iter().zip(&mut factors) {
+                    *factor *= guide.weight_factor(idx, *option);
+                }
+            }
 
-            // Use weighted choice based on base tile weights
-            *possible_options
-                .choose_weighted(&mut self.rng, |&idx| {
-                    let base_idx = idx / 4;
-                    self.tileset.tile_weights[base_idx]
+            // Use weighted choice based on base tile weights, steered towards the count
+            // constraints and the guide. Only fails if there is nothing left to choose from
+            let choices: Vec<(usize, f32)> = possible_options.into_iter().zip(factors).collect();
+            choices
+                .choose_weighted(&mut self.rng, |&(idx, factor)| {
+                    self.tileset.weight(idx) * factor
                 })
-                .map_err(|e| anyhow::anyhow!("Failed weighted choice: {}", e))?
+                .map_err(|_| Contradiction {
+                    x,
+                    y,
+                    neighbor: None,
+                })?
+                .0
         };
-        let (x, y) = xy;
-        *self.wave.get_mut(x, y) = WaveTile::Observed(observation);
 
-        // propagate
-        let mut propagation_queue = VecDeque::new();
-        propagation_queue.push_back((x, y));
-        let mut visited = HashSet::new();
+        if let Some(limits) = self.config.backtracking {
+            self.history.push_back(Decision {
+                x,
+                y,
+                observation,
+                trail: Vec::new(),
+            });
+            if self.history.len() > limits.max_depth {
+                self.history.pop_front();
+            }
+        }
 
-        while let Some((x, y)) = propagation_queue.pop_front() {
-            if visited.contains(&(x, y)) {
-                continue;
-            } else {
-                visited.insert((x, y));
-            }
-
-            let center = self.wave.get(x, y);
-            let children = self.get_children(x, y);
-            let center_possible_options = center.possible_options();
-
-            for ((child_x, child_y), side) in children {
-                let child = self.wave.get(child_x, child_y);
-                let disallowed_options = match child {
-                    WaveTile::Unobserved(_) => {
-                        let child_possible = child.possible_options();
-                        let mut disallowed = Vec::new();
-
-                        // For each child option, check if ANY center option allows it
-                        'child_loop: for &child_opt in &child_possible {
-                            for &center_opt in center_possible_options.iter() {
-                                if self.is_allowed(center_opt, child_opt, side) {
-                                    continue 'child_loop;
-                                }
-                            }
-                            // No center tile allows this child tile - it's disallowed
-                            disallowed.push(child_opt);
-                        }
+        let removed = self.observe(x, y, observation);
 
-                        disallowed
-                    }
-                    WaveTile::Observed(_) => Vec::new(),
+        if let Err(contradiction) = self.propagate(x, y, &removed, observer) {
+            return self.backtrack(contradiction, observer);
+        }
+
+        observer.cell_observed(self, x, y, observation);
+        Ok(())
+    }
+
+    /// Undo observations until the wave is consistent again, banning each undone choice.
+    /// Returns the latest contradiction if backtracking is disabled or runs out of retries/history
+    fn backtrack(
+        &mut self,
+        mut contradiction: Contradiction,
+        observer: &mut dyn WfcObserver,
+    ) -> Result<(), Contradiction> {
+        let Some(limits) = self.config.backtracking else {
+            return Err(contradiction);
+        };
+
+        loop {
+            if self.retries >= limits.max_retries {
+                return Err(contradiction);
+            }
+            self.retries += 1;
+
+            let Some(decision) = self.history.pop_back() else {
+                return Err(contradiction);
+            };
+            let (x, y, observation) = (decision.x, decision.y, decision.observation);
+            self.undo(decision);
+
+            // banning the observation is a consequence of the decisions before it, so it's
+            // undone along with them
+            let idx = y * self.wave.width + x;
+            if let WaveTile::Unobserved(items) = &self.wave.tiles[idx]
+                && items.count() == 1
+            {
+                // nothing else left to try here. The tile is left alone, since the removal
+                // would never be propagated
+                contradiction = Contradiction {
+                    x,
+                    y,
+                    neighbor: None,
                 };
+                continue;
+            }
 
-                if let WaveTile::Unobserved(items) = self.wave.get_mut(child_x, child_y) {
-                    for option in disallowed_options {
-                        items[option] = false;
-                    }
+            let result = self
+                .remove_options(vec![(idx, observation)], observer)
+                .and_then(|()| self.enforce_global_constraints(x, y, observer));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(c) => contradiction = c,
+            }
+        }
+    }
 
-                    if !visited.contains(&(child_x, child_y)) {
-                        propagation_queue.push_back((child_x, child_y));
+    /// Replays the trail of `decision` backwards, bringing the wave (and support counts) back to
+    /// how they were right before it
+    fn undo(&mut self, decision: Decision) {
+        let mut touched = Vec::with_capacity(decision.trail.len());
+        for change in decision.trail.into_iter().rev() {
+            match change {
+                Change::Removed { idx, option } => {
+                    if let WaveTile::Unobserved(options) = &mut self.wave.tiles[idx] {
+                        options.insert(option);
+                    }
+                    if let Some(supports) = &mut self.supports {
+                        supports.restore(&self.masks, &self.wave, idx, option);
                     }
+                    touched.push(idx);
+                }
+                Change::Observed { idx, option } => {
+                    let mut options = BitSet::empty(self.masks.len());
+                    options.insert(option);
+                    self.wave.tiles[idx] = WaveTile::Unobserved(options);
+                    self.unobserved += 1;
+                    touched.push(idx);
                 }
             }
         }
 
+        // the candidates of these tiles went stale, every other tile's are still there
+        touched.sort_unstable();
+        touched.dedup();
+        for idx in touched {
+            self.tile_changed(idx);
+        }
+    }
+
+    fn index_to_tile(&self, index: usize) -> Tile {
+        self.tileset.variants[index]
+    }
+
+    /// step until finished or in a contradictory state. Returns the contradiction if ran into one
+    pub fn step_all(&mut self, observer: &mut dyn WfcObserver) -> Result<(), Contradiction> {
+        if self.is_finished() {
+            // nothing to step, e.g. everything was pinned, but observers still get to know
+            observer.finished(self);
+        }
+        while !self.is_finished() {
+            self.step(observer)?;
+        }
         Ok(())
     }
 
-    #[inline]
-    fn get_children(&self, x: usize, y: usize) -> Vec<((usize, usize), u8)> {
-        let mut children = Vec::new();
-        if y > 0 {
-            children.push(((x, y - 1), 0));
+    pub fn bitmap(&self) -> Bitmap {
+        let tile_size = self.tileset.tile_size;
+        let width = self.wave.width * tile_size;
+        let height = self.wave.height * tile_size;
+
+        let mut bits = vec![MaterialId::EMPTY; width * height];
+
+        for slot_y in 0..self.wave.height {
+            for slot_x in 0..self.wave.width {
+                if let WaveTile::Observed(i) = self.wave.get(slot_x, slot_y) {
+                    let tile = self.index_to_tile(*i);
+                    let bitmap = self
+                        .tileset
+                        .base_tile(*i)
+                        .transformed_bitmap(tile.rotation, tile.reflected);
+                    for (tile_y, row) in bitmap.iter().enumerate() {
+                        let y = slot_y * tile_size + tile_y;
+                        for (tile_x, bit) in row.iter().enumerate() {
+                            let x = slot_x * tile_size + tile_x;
+                            bits[y * width + x] = *bit;
+                        }
+                    }
+                }
+            }
         }
-        if x > 0 {
-            children.push(((x - 1, y), 1));
+
+        Bitmap {
+            bits,
+            width,
+            height,
+            materials: self.tileset.materials.clone(),
         }
-        if y < self.wave.height - 1 {
-            children.push(((x, y + 1), 2));
+    }
+
+    /// Renders the current wave by blitting the rotated (and possibly mirrored) image of every observed tile, at the
+    /// resolution of the tile images. Unobserved tiles are left black. Errors if the tileset
+    /// has no images
+    pub fn render(&self) -> Result<DynamicImage, Error> {
+        let tile_size = self
+            .tileset
+            .image_size()
+            .ok_or(anyhow::anyhow!("tileset has no tile images to render"))?;
+        let width = self.wave.width as u32 * tile_size;
+        let height = self.wave.height as u32 * tile_size;
+
+        let mut img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(width, height);
+
+        for y in 0..self.wave.height {
+            for x in 0..self.wave.width {
+                if let WaveTile::Observed(tile_idx) = self.wave.get(x, y) {
+                    let tile = self.index_to_tile(*tile_idx);
+                    let tile_name = &self.tileset.tile_names[tile.base_tile_idx];
+                    let tile_img = self
+                        .tileset
+                        .tiles
+                        .get(tile_name)
+                        .and_then(|base| base.image(tile.rotation, tile.reflected))
+                        .ok_or(anyhow::anyhow!("tile '{}' has no image", tile_name))?
+                        .to_rgb8();
+
+                    let px = x as u32 * tile_size;
+                    let py = y as u32 * tile_size;
+                    img.copy_from(&tile_img, px, py)?;
+                }
+            }
         }
-        if x < self.wave.width - 1 {
-            children.push(((x + 1, y), 3));
+
+        Ok(DynamicImage::from(img))
+    }
+
+    /// Image of the current wave for animations: the tile images if the tileset has them,
+    /// otherwise the bitmap scaled up so every bit is `BIT_SCALE` pixels wide
+    pub fn frame(&self) -> DynamicImage {
+        if let Ok(img) = self.render() {
+            return img;
         }
 
-        children
+        let img = self.bitmap().render_to_image();
+        img.resize_exact(
+            img.width() * BIT_SCALE,
+            img.height() * BIT_SCALE,
+            image::imageops::FilterType::Nearest,
+        )
     }
+}
 
-    #[inline]
-    fn is_allowed(&self, tile_one_idx: usize, tile_two_idx: usize, side: u8) -> bool {
-        self.tileset.allowed_neighbors[tile_one_idx][side as usize][tile_two_idx]
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image::GenericImageView;
+
+    use super::*;
+    use crate::procgen::material::Material;
+    use crate::procgen::tileset::{island_race_pins, make_island_race_tileset};
+
+    /// Checks that every pair of neighboring tiles is allowed by the tileset
+    fn assert_consistent(wfc: &WaveFunctionCollapse) {
+        let wave = &wfc.wave;
+        for y in 0..wave.height {
+            for x in 0..wave.width {
+                let WaveTile::Observed(option) = wave.get(x, y) else {
+                    panic!("tile ({}, {}) wasn't observed", x, y);
+                };
+                for side in 0..4 {
+                    let Some((nx, ny)) = wave.neighbor(x, y, side) else {
+                        continue;
+                    };
+                    let WaveTile::Observed(neighbor) = wave.get(nx, ny) else {
+                        panic!("tile ({}, {}) wasn't observed", nx, ny);
+                    };
+                    assert!(
+                        wfc.masks[*option][side as usize].contains(*neighbor),
+                        "({}, {}) doesn't fit next to ({}, {})",
+                        nx,
+                        ny,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
     }
 
-    fn index_to_tile(&self, index: usize) -> Tile {
-        let base_index = index / 4;
-        let rotation = index % 4;
-        Tile {
-            base_tile_idx: base_index,
-            rotation: rotation.try_into().unwrap(),
+    #[test]
+    fn pins_parse() {
+        assert_eq!(
+            "3,4,road_end".parse::<Pin>().unwrap(),
+            Pin {
+                x: 3,
+                y: 4,
+                tile: "road_end".to_string(),
+                rotation: None,
+            }
+        );
+        assert_eq!(
+            " 0, 12 ,road_turn, 3".parse::<Pin>().unwrap(),
+            Pin {
+                x: 0,
+                y: 12,
+                tile: "road_turn".to_string(),
+                rotation: Some(3),
+            }
+        );
+        for invalid in [
+            "1,2",
+            "1,2,road_end,3,4",
+            "a,2,road_end",
+            "1,-2,road_end",
+            "1,2,road_end,x",
+        ] {
+            assert!(invalid.parse::<Pin>().is_err(), "'{}' parsed", invalid);
         }
     }
 
-    /// step until finished or in a contradictory state. Returns whether ran into a contradictory state
-    pub fn step_all(&mut self, show_progress: bool, save_bitmaps: bool) -> (bool, Vec<Bitmap>) {
-        let total = (self.wave.width * self.wave.height) as u64;
+    #[test]
+    fn rejects_pins_that_cant_be_placed() {
+        let tileset = make_island_race_tileset();
+        let pinned = |pin: &str| {
+            let config = WfcConfig {
+                pins: vec![pin.parse().unwrap()],
+                ..WfcConfig::default()
+            };
+            WaveFunctionCollapse::new(tileset.clone(), 4, 3, 0, config)
+        };
+        assert!(pinned("3,2,road_end,1").is_ok());
+        for (pin, message) in [
+            ("4,0,road_end", "outside"),
+            ("0,3,road_end", "outside"),
+            ("0,0,road_end,4", "rotation"),
+            ("0,0,lava", "not in tileset"),
+        ] {
+            let Err(err) = pinned(pin) else {
+                panic!("'{}' was pinned", pin);
+            };
+            assert!(err.to_string().contains(message), "{}: {}", pin, err);
+        }
+    }
 
-        let progress = if show_progress {
-            let bar = ProgressBar::new(total);
-            bar.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-                    .unwrap()
-                    .progress_chars("#>-"),
-            );
-            Some(bar)
-        } else {
-            None
+    /// Tiles in the order they were observed
+    #[derive(Default)]
+    struct ObservedOrder(Vec<(usize, usize)>);
+
+    impl WfcObserver for ObservedOrder {
+        fn cell_observed(&mut self, _wfc: &WaveFunctionCollapse, x: usize, y: usize, _: usize) {
+            self.0.push((x, y));
+        }
+    }
+
+    #[test]
+    fn scanline_observes_in_row_major_order() {
+        let config = WfcConfig {
+            heuristic: SelectionHeuristic::Scanline,
+            ..WfcConfig::default()
         };
+        let mut wfc =
+            WaveFunctionCollapse::new(make_island_race_tileset(), 5, 4, 2, config).unwrap();
+        let mut order = ObservedOrder::default();
+        wfc.step_all(&mut order).unwrap();
+
+        let row_major: Vec<(usize, usize)> =
+            (0..4).flat_map(|y| (0..5).map(move |x| (x, y))).collect();
+        assert_eq!(order.0, row_major);
+    }
+
+    #[test]
+    fn mrv_observes_tiles_with_fewest_options() {
+        let config = WfcConfig {
+            heuristic: SelectionHeuristic::Mrv,
+            ..WfcConfig::default()
+        };
+        let mut wfc =
+            WaveFunctionCollapse::new(make_island_race_tileset(), 6, 6, 4, config).unwrap();
+        let mut order = ObservedOrder::default();
+        while !wfc.is_finished() {
+            let counts: Vec<Option<usize>> = wfc
+                .wave
+                .tiles
+                .iter()
+                .map(|tile| match tile {
+                    WaveTile::Unobserved(options) => Some(options.count()),
+                    WaveTile::Observed(_) => None,
+                })
+                .collect();
+            let fewest = counts.iter().flatten().min().copied();
 
-        let mut bitmaps = Vec::new();
+            wfc.step(&mut order).unwrap();
+            let &(x, y) = order.0.last().unwrap();
+            assert_eq!(counts[y * wfc.wave.width + x], fewest, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn neighbors_wrap_when_periodic() {
+        let wave = |periodic| Wave {
+            tiles: Vec::new(),
+            width: 4,
+            height: 3,
+            periodic,
+        };
+        let (flat, torus) = (wave(false), wave(true));
+        for side in 0..4 {
+            // inner tiles have the same neighbors either way
+            assert_eq!(flat.neighbor(1, 1, side), torus.neighbor(1, 1, side));
+        }
+        assert_eq!(flat.neighbor(0, 0, 0), None);
+        assert_eq!(flat.neighbor(0, 0, 1), None);
+        assert_eq!(flat.neighbor(3, 2, 2), None);
+        assert_eq!(flat.neighbor(3, 2, 3), None);
+        assert_eq!(torus.neighbor(0, 0, 0), Some((0, 2)));
+        assert_eq!(torus.neighbor(0, 0, 1), Some((3, 0)));
+        assert_eq!(torus.neighbor(3, 2, 2), Some((3, 0)));
+        assert_eq!(torus.neighbor(3, 2, 3), Some((0, 2)));
+    }
 
-        for _ in 0..total {
-            if let Err(_) = self.step() {
-                if let Some(bar) = progress {
-                    bar.finish_with_message("contradiction!");
+    #[test]
+    fn periodic_output_tiles_seamlessly() {
+        for propagator in [Propagator::Masks, Propagator::SupportCount] {
+            let config = WfcConfig {
+                backtracking: Some(BacktrackConfig::default()),
+                propagator,
+                periodic: true,
+                ..WfcConfig::default()
+            };
+            let mut wfc =
+                WaveFunctionCollapse::new(make_island_race_tileset(), 7, 5, 1, config).unwrap();
+            assert_eq!(wfc.step_all(&mut ()), Ok(()));
+            assert_consistent(&wfc);
+
+            // edges that touch have the same bits, so the first and last rows and columns match
+            let bitmap = wfc.bitmap();
+            let (w, h) = (bitmap.width, bitmap.height);
+            let bit = |x: usize, y: usize| bitmap.bits[y * w + x];
+            for y in 0..h {
+                assert_eq!(bit(0, y), bit(w - 1, y), "row {}", y);
+            }
+            for x in 0..w {
+                assert_eq!(bit(x, 0), bit(x, h - 1), "column {}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn renders_tile_images() {
+        let xml =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("src/procgen/tilemaps/knots/tileset.xml");
+        let tileset = crate::procgen::parse_tileset_xml(xml, None).unwrap();
+        let tile_size = tileset.image_size().unwrap();
+        let mut wfc = WaveFunctionCollapse::new(tileset, 5, 3, 0, WfcConfig::default()).unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+
+        let img = wfc.render().unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (5 * tile_size, 3 * tile_size));
+        for y in 0..3 {
+            for x in 0..5 {
+                let WaveTile::Observed(option) = wfc.wave.get(x, y) else {
+                    unreachable!("the wave is finished");
+                };
+                let variant = wfc.tileset.variants[*option];
+                let expected = wfc
+                    .tileset
+                    .base_tile(*option)
+                    .image(variant.rotation, variant.reflected)
+                    .unwrap()
+                    .to_rgb8();
+                let (px, py) = (x as u32 * tile_size, y as u32 * tile_size);
+                let rendered = img.view(px, py, tile_size, tile_size).to_image();
+                assert_eq!(rendered, expected, "tile ({}, {})", x, y);
+            }
+        }
+
+        // without images there is nothing to render, but frames fall back to the bitmap
+        let mut wfc =
+            WaveFunctionCollapse::new(make_island_race_tileset(), 5, 3, 0, WfcConfig::default())
+                .unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+        assert!(wfc.render().is_err());
+        assert_eq!(wfc.frame().width(), 5 * 4 * BIT_SCALE);
+    }
+
+    #[test]
+    fn bitmap_places_tiles_of_any_size() {
+        let json = r#"{
+            "legend": { "R": "Road", "G": "Grass" },
+            "tiles": [
+                { "name": "grass", "bitmap": ["GGG", "GGG", "GGG"] },
+                { "name": "road", "symmetry": "I", "bitmap": ["GRG", "GRG", "GRG"] },
+                { "name": "end", "symmetry": "T", "bitmap": ["GGG", "GRG", "GRG"] }
+            ]
+        }"#;
+        let tileset = crate::procgen::json_tileset::parse_tileset_json_str(json).unwrap();
+        let mut wfc = WaveFunctionCollapse::new(tileset, 4, 3, 1, WfcConfig::default()).unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+
+        let bitmap = wfc.bitmap();
+        assert_eq!((bitmap.width, bitmap.height), (12, 9));
+        for y in 0..3 {
+            for x in 0..4 {
+                let WaveTile::Observed(option) = wfc.wave.get(x, y) else {
+                    unreachable!("the wave is finished");
+                };
+                let variant = wfc.tileset.variants[*option];
+                let expected = wfc
+                    .tileset
+                    .base_tile(*option)
+                    .transformed_bitmap(variant.rotation, variant.reflected);
+                for (ty, row) in expected.iter().enumerate() {
+                    for (tx, bit) in row.iter().enumerate() {
+                        let idx = (y * 3 + ty) * bitmap.width + x * 3 + tx;
+                        assert_eq!(bitmap.bits[idx], *bit, "tile ({}, {})", x, y);
+                    }
                 }
-                return (true, bitmaps);
             }
-            if let Some(ref bar) = progress {
-                bar.inc(1);
+        }
+    }
+
+    #[test]
+    fn masks_match_allowed_neighbors() {
+        let tileset = make_island_race_tileset();
+        let masks = compatibility_masks(&tileset);
+        assert_eq!(masks.len(), tileset.variants.len());
+        for (option, sides) in masks.iter().enumerate() {
+            for (side, mask) in sides.iter().enumerate() {
+                let allowed = &tileset.allowed_neighbors[option][side];
+                for other in 0..masks.len() {
+                    assert_eq!(mask.contains(other), allowed[other]);
+                    // seen from the other tile, this one is on the opposite side
+                    assert_eq!(
+                        mask.contains(other),
+                        masks[other][(side + 2) % 4].contains(option)
+                    );
+                }
             }
-            if save_bitmaps {
-                bitmaps.push(self.bitmap());
+        }
+    }
+
+    #[test]
+    fn backtracking_recovers_from_contradictions() {
+        // closed road loops on a small wave run into contradictions easily
+        let config = |propagator, backtracking| WfcConfig {
+            backtracking,
+            propagator,
+            pins: island_race_pins(10, 10, Some(RoadConstraint::Loop)),
+            roads: Some(RoadConstraint::Loop),
+            ..WfcConfig::default()
+        };
+        let tileset = make_island_race_tileset();
+
+        for propagator in [Propagator::Masks, Propagator::SupportCount] {
+            let mut plain =
+                WaveFunctionCollapse::new(tileset.clone(), 10, 10, 5, config(propagator, None))
+                    .unwrap();
+            assert!(plain.step_all(&mut ()).is_err());
+
+            let backtracking = Some(BacktrackConfig::default());
+            let mut wfc = WaveFunctionCollapse::new(
+                tileset.clone(),
+                10,
+                10,
+                5,
+                config(propagator, backtracking),
+            )
+            .unwrap();
+            assert_eq!(wfc.step_all(&mut ()), Ok(()));
+            assert!(wfc.retries > 0);
+            assert_consistent(&wfc);
+            // undoing kept the counts in line with the wave
+            if let Some(supports) = &wfc.supports {
+                assert_eq!(supports, &SupportCounts::new(&wfc.masks, &wfc.wave));
             }
         }
+    }
 
-        if let Some(bar) = progress {
-            bar.finish_with_message("done");
+    #[test]
+    fn contradictions_point_at_the_empty_tile() {
+        let pin_contradiction = |result: Result<WaveFunctionCollapse, Error>| match result {
+            Ok(_) => panic!("pins fit"),
+            Err(e) => e.downcast::<Contradiction>().unwrap(),
+        };
+        let tileset = make_island_race_tileset();
+        let pin = |x, tile: &str, rotation| Pin {
+            x,
+            y: 0,
+            tile: tile.to_string(),
+            rotation,
+        };
+        for propagator in [Propagator::Masks, Propagator::SupportCount] {
+            // no tile has space on one side and a road on the other, so propagating from the
+            // space pin already took every horizontal road from (2, 0)
+            for rotation in [1, 3] {
+                let config = WfcConfig {
+                    propagator,
+                    pins: vec![
+                        pin(0, "pure_space", None),
+                        pin(2, "road_straight", Some(rotation)),
+                    ],
+                    ..WfcConfig::default()
+                };
+                let contradiction =
+                    pin_contradiction(WaveFunctionCollapse::new(tileset.clone(), 3, 1, 0, config));
+                assert_eq!(
+                    contradiction,
+                    Contradiction {
+                        x: 2,
+                        y: 0,
+                        neighbor: None,
+                    }
+                );
+            }
+
+            // grass can't be pinned right next to space
+            let config = WfcConfig {
+                propagator,
+                pins: vec![pin(0, "pure_space", None), pin(1, "pure_grass", None)],
+                ..WfcConfig::default()
+            };
+            let contradiction =
+                pin_contradiction(WaveFunctionCollapse::new(tileset.clone(), 3, 1, 0, config));
+            assert_eq!((contradiction.x, contradiction.y), (1, 0));
+            assert_eq!(contradiction.neighbor, None);
+
+            // contradictions while stepping name a tile that really is empty, next to the
+            // neighbor they came from
+            let config = WfcConfig {
+                propagator,
+                pins: island_race_pins(10, 10, Some(RoadConstraint::Loop)),
+                roads: Some(RoadConstraint::Loop),
+                ..WfcConfig::default()
+            };
+            let mut wfc = WaveFunctionCollapse::new(tileset.clone(), 10, 10, 5, config).unwrap();
+            let contradiction = wfc.step_all(&mut ()).unwrap_err();
+            let (x, y) = (contradiction.x, contradiction.y);
+            assert!(
+                matches!(wfc.wave.get(x, y), WaveTile::Unobserved(options) if options.is_empty())
+            );
+            if let Some(neighbor) = contradiction.neighbor {
+                assert!((0..4).any(|side| wfc.wave.neighbor(x, y, side) == Some(neighbor)));
+            }
         }
+    }
 
-        (false, bitmaps)
+    #[test]
+    fn undo_restores_wave_and_support_counts() {
+        let config = WfcConfig {
+            backtracking: Some(BacktrackConfig::default()),
+            propagator: Propagator::SupportCount,
+            ..WfcConfig::default()
+        };
+        let mut wfc =
+            WaveFunctionCollapse::new(make_island_race_tileset(), 8, 8, 1, config).unwrap();
+        for _ in 0..10 {
+            wfc.step(&mut ()).unwrap();
+        }
+        let wave = wfc.wave.clone();
+        let unobserved = wfc.unobserved;
+
+        for _ in 0..5 {
+            wfc.step(&mut ()).unwrap();
+        }
+        for _ in 0..5 {
+            let decision = wfc.history.pop_back().unwrap();
+            wfc.undo(decision);
+        }
+        assert_eq!(wfc.wave, wave);
+        assert_eq!(wfc.unobserved, unobserved);
+        assert_eq!(
+            wfc.supports,
+            Some(SupportCounts::new(&wfc.masks, &wfc.wave))
+        );
     }
 
-    pub fn bitmap(&self) -> Bitmap {
-        let width = self.wave.width * TILE_SIZE;
-        let height = self.wave.height * TILE_SIZE;
+    #[test]
+    fn repeated_and_stale_removals_are_skipped() {
+        for propagator in [Propagator::Masks, Propagator::SupportCount] {
+            let config = WfcConfig {
+                backtracking: Some(BacktrackConfig::default()),
+                propagator,
+                ..WfcConfig::default()
+            };
+            let mut wfc =
+                WaveFunctionCollapse::new(make_island_race_tileset(), 8, 8, 1, config).unwrap();
+            for _ in 0..10 {
+                wfc.step(&mut ()).unwrap();
+            }
+            let wave = wfc.wave.clone();
 
-        let mut bits = vec![Bit::Empty; width * height];
+            // an unobserved tile, one of its options and an option it already lost
+            let (idx, present, gone) = (0..wave.tiles.len())
+                .find_map(|idx| {
+                    let WaveTile::Unobserved(options) = &wave.tiles[idx] else {
+                        return None;
+                    };
+                    let present = options.iter().next()?;
+                    let gone = (0..wfc.masks.len()).find(|&option| !options.contains(option))?;
+                    (options.count() > 1).then_some((idx, present, gone))
+                })
+                .unwrap();
+
+            wfc.history.push_back(Decision {
+                x: idx % wave.width,
+                y: idx / wave.width,
+                observation: present,
+                trail: Vec::new(),
+            });
+            let removals = vec![(idx, gone), (idx, present), (idx, present), (idx, gone)];
+            assert_eq!(wfc.remove_options(removals, &mut ()), Ok(()));
+
+            let trail = &wfc.history.back().unwrap().trail;
+            let removed_here: Vec<usize> = trail
+                .iter()
+                .filter_map(|change| match *change {
+                    Change::Removed { idx: i, option } if i == idx => Some(option),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(removed_here, vec![present], "{:?}", propagator);
+            if let Some(supports) = &wfc.supports {
+                assert_eq!(supports, &SupportCounts::new(&wfc.masks, &wfc.wave));
+            }
 
-        for slot_y in 0..self.wave.height {
-            for slot_x in 0..self.wave.width {
-                if let WaveTile::Observed(i) = self.wave.get(slot_x, slot_y) {
-                    let tile = self.index_to_tile(*i);
-                    let tile_name = &self.tileset.tile_names[tile.base_tile_idx];
-                    let base_bitmap = &self.tileset.tiles.get(tile_name).unwrap().bitmap;
-                    for tile_y in 0..TILE_SIZE {
-                        let y = slot_y * TILE_SIZE + tile_y;
-                        for tile_x in 0..TILE_SIZE {
-                            let bit = if tile.rotation == 0 {
-                                base_bitmap[tile_y][tile_x]
-                            } else if tile.rotation == 1 {
-                                base_bitmap[tile_x][TILE_SIZE - tile_y - 1]
-                            } else if tile.rotation == 2 {
-                                base_bitmap[TILE_SIZE - tile_y - 1][TILE_SIZE - tile_x - 1]
-                            } else {
-                                base_bitmap[TILE_SIZE - tile_x - 1][tile_y]
-                            };
+            let decision = wfc.history.pop_back().unwrap();
+            wfc.undo(decision);
+            assert_eq!(wfc.wave, wave, "{:?}", propagator);
+        }
+    }
 
-                            let x = slot_x * TILE_SIZE + tile_x;
-                            bits[y * width + x] = bit;
-                        }
+    #[test]
+    fn propagators_pick_the_same_tiles() {
+        let tileset = make_island_race_tileset();
+        let heuristics = [
+            SelectionHeuristic::Entropy,
+            SelectionHeuristic::Mrv,
+            SelectionHeuristic::Scanline,
+        ];
+        for seed in 0..4 {
+            for heuristic in heuristics {
+                for backtracking in [None, Some(BacktrackConfig::default())] {
+                    for periodic in [false, true] {
+                        let run = |propagator| {
+                            let config = WfcConfig {
+                                backtracking,
+                                propagator,
+                                heuristic,
+                                periodic,
+                                roads: Some(RoadConstraint::Loop),
+                                ..WfcConfig::default()
+                            };
+                            let mut wfc =
+                                WaveFunctionCollapse::new(tileset.clone(), 9, 7, seed, config)
+                                    .unwrap();
+                            let result = wfc.step_all(&mut ());
+                            (result.is_ok(), wfc.steps(), wfc.retries, wfc.bitmap().bits)
+                        };
+
+                        assert_eq!(
+                            run(Propagator::Masks),
+                            run(Propagator::SupportCount),
+                            "seed {}, {:?}, backtracking {}, periodic {}",
+                            seed,
+                            heuristic,
+                            backtracking.is_some(),
+                            periodic,
+                        );
                     }
                 }
             }
         }
+    }
 
-        Bitmap {
-            bits,
-            width,
-            height,
+    #[test]
+    fn regenerate_keeps_bits_outside_region() {
+        let tileset = make_island_race_tileset();
+        let config = WfcConfig {
+            backtracking: Some(BacktrackConfig::default()),
+            ..WfcConfig::default()
+        };
+        let mut wfc = WaveFunctionCollapse::new(tileset.clone(), 8, 6, 0, config.clone()).unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+        let before = wfc.bitmap();
+
+        let region = Region {
+            x: 2,
+            y: 1,
+            width: 4,
+            height: 3,
+        };
+        let mut regen =
+            WaveFunctionCollapse::regenerate(tileset, &before, region, 1, config).unwrap();
+        assert_eq!(regen.unobserved, 12);
+        assert_eq!(regen.step_all(&mut ()), Ok(()));
+        let after = regen.bitmap();
+
+        for (idx, (old, new)) in before.bits.iter().zip(&after.bits).enumerate() {
+            let (x, y) = (idx % before.width / 4, idx / before.width / 4);
+            if !region.contains(x, y) {
+                assert_eq!(old, new, "bit {} outside the region changed", idx);
+            }
+        }
+    }
+
+    #[test]
+    fn regenerate_rejects_regions_outside_the_wave() {
+        let tileset = make_island_race_tileset();
+        let mut wfc =
+            WaveFunctionCollapse::new(tileset.clone(), 8, 6, 0, WfcConfig::default()).unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+        let bitmap = wfc.bitmap();
+
+        for region in [
+            "6,0,3,1",
+            "0,5,1,2",
+            "1,0,18446744073709551615,1",
+            "0,18446744073709551615,1,2",
+        ] {
+            let region: Region = region.parse().unwrap();
+            let result = WaveFunctionCollapse::regenerate(
+                tileset.clone(),
+                &bitmap,
+                region,
+                1,
+                WfcConfig::default(),
+            );
+            assert!(result.is_err(), "{:?} was accepted", region);
         }
     }
 
-    // /// renders current wave to texture/image
-    // pub fn render(&self) -> Result<DynamicImage, Error> {
-    //     let width = self.wave.width * self.tileset.tile_size;
-    //     let height = self.wave.height * self.tileset.tile_size;
-
-    //     let mut img: ImageBuffer<Rgb<u8>, Vec<u8>> =
-    //         ImageBuffer::new(width.try_into().unwrap(), height.try_into().unwrap());
-
-    //     for x in 0..self.wave.width {
-    //         for y in 0..self.wave.height {
-    //             let wave_slot = self.wave.get(x, y);
-    //             match wave_slot {
-    //                 WaveTile::Observed(tile_idx) => {
-    //                     let tile = self.index_to_tile(*tile_idx);
-    //                     let tile_name = &self.tileset.tile_names[tile.base_tile_idx];
-    //                     let base_img = &self
-    //                         .tileset
-    //                         .tiles
-    //                         .get(tile_name)
-    //                         .expect("tile not found")
-    //                         .img;
-    //                     let rotated_img = match tile.rotation {
-    //                         0 => base_img.clone(),
-    //                         1 => base_img.rotate270(),
-    //                         2 => base_img.rotate180(),
-    //                         3 => base_img.rotate90(),
-    //                         _ => base_img.clone(),
-    //                     };
-
-    //                     let tile_img = rotated_img.to_rgb8();
-
-    //                     let px = (x * self.tileset.tile_size) as u32;
-    //                     let py = (y * self.tileset.tile_size) as u32;
-
-    //                     img.copy_from(&tile_img, px, py)?;
-    //                 }
-    //                 WaveTile::Unobserved(_) => (),
-    //             }
-    //         }
-    //     }
-
-    //     Ok(DynamicImage::from(img))
-    // }
+    #[test]
+    fn periodic_regeneration_wraps_around() {
+        let tileset = make_island_race_tileset();
+        let config = WfcConfig {
+            backtracking: Some(BacktrackConfig::default()),
+            periodic: true,
+            ..WfcConfig::default()
+        };
+        let mut wfc = WaveFunctionCollapse::new(tileset.clone(), 8, 6, 2, config.clone()).unwrap();
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+
+        // the corner tile's neighbors across both edges are outside the region
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let mut regen =
+            WaveFunctionCollapse::regenerate(tileset, &wfc.bitmap(), region, 3, config).unwrap();
+        assert_eq!(regen.step_all(&mut ()), Ok(()));
+        assert_consistent(&regen);
+    }
+
+    #[test]
+    fn restored_state_continues_the_same() {
+        let tileset = make_island_race_tileset();
+        let config = WfcConfig {
+            backtracking: Some(BacktrackConfig::default()),
+            propagator: Propagator::SupportCount,
+            heuristic: SelectionHeuristic::Entropy,
+            roads: Some(RoadConstraint::Connected),
+            counts: CountConstraints {
+                min_road_length: Some(10),
+                ..CountConstraints::default()
+            },
+            ..WfcConfig::default()
+        };
+        let mut wfc = WaveFunctionCollapse::new(tileset.clone(), 10, 8, 3, config).unwrap();
+        for _ in 0..20 {
+            wfc.step(&mut ()).unwrap();
+        }
+
+        let json = serde_json::to_string(&wfc.snapshot()).unwrap();
+        let state: SolverState = serde_json::from_str(&json).unwrap();
+        let mut restored = WaveFunctionCollapse::restore(tileset, state).unwrap();
+        assert_eq!(restored.steps(), 20);
+
+        assert_eq!(wfc.step_all(&mut ()), Ok(()));
+        assert_eq!(restored.step_all(&mut ()), Ok(()));
+        assert_eq!(wfc.steps(), restored.steps());
+        assert_eq!(wfc.bitmap().bits, restored.bitmap().bits);
+    }
+
+    #[test]
+    fn restore_rejects_other_tileset() {
+        let tileset = make_island_race_tileset();
+        let wfc =
+            WaveFunctionCollapse::new(tileset.clone(), 4, 4, 0, WfcConfig::default()).unwrap();
+        let state = wfc.snapshot();
+
+        let mut other = tileset.clone();
+        other.tile_weights[0] *= 2.0;
+        assert!(WaveFunctionCollapse::restore(other, state.clone()).is_err());
+
+        let mut other = tileset;
+        other
+            .materials
+            .add(Material {
+                name: "lava".to_string(),
+                color: [1.0, 0.3, 0.0, 1.0],
+                solid: true,
+                island: true,
+                flat: false,
+                road: false,
+            })
+            .unwrap();
+        assert!(WaveFunctionCollapse::restore(other, state).is_err());
+    }
 }